@@ -0,0 +1,87 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::cmp;
+use std::time::Duration;
+
+use rand::Rng;
+
+// Exponential backoff: each call to next_delay() doubles the delay, starting
+// at `base` and capped at `max`.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            attempt: 0
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        // Avoid overflow once the delay is well past the cap anyway.
+        let factor = 1u32.checked_shl(cmp::min(self.attempt, 31)).unwrap();
+        let delay = self.base.checked_mul(factor).unwrap_or(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        cmp::min(delay, self.max)
+    }
+}
+
+// Adds up to a quarter again to `delay` so that many instances backing off
+// at once don't all retry at the same moment.
+pub fn with_jitter(delay: Duration) -> Duration {
+    let max_jitter_ms = (delay.as_secs() * 1000 +
+                         u64::from(delay.subsec_millis())) / 4;
+    let jitter_ms = rand::thread_rng().gen_range(0, max_jitter_ms + 1);
+
+    delay + Duration::from_millis(jitter_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_double_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(500),
+                                       Duration::from_secs(30));
+        let delays: Vec<u64> = (0..9)
+            .map(|_| {
+                let d = backoff.next_delay();
+                d.as_secs() * 1000 + u64::from(d.subsec_millis())
+            })
+            .collect();
+
+        assert_eq!(delays, vec![500, 1000, 2000, 4000, 8000, 16000, 30000,
+                                30000, 30000]);
+    }
+
+    #[test]
+    fn many_attempts_stay_capped() {
+        let max = Duration::from_secs(30);
+        let mut backoff = Backoff::new(Duration::from_millis(500), max);
+
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= max);
+        }
+        assert_eq!(backoff.next_delay(), max);
+    }
+
+    #[test]
+    fn jitter_within_a_quarter() {
+        let delay = Duration::from_secs(4);
+
+        for _ in 0..100 {
+            let jittered = with_jitter(delay);
+            assert!(jittered >= delay);
+            assert!(jittered <= Duration::from_secs(5));
+        }
+    }
+}
@@ -3,32 +3,254 @@
  */
 
 
-use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
-use std::net::Ipv4Addr;
-use std::path::Path;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use reqwest::header::CONTENT_TYPE;
 
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use slog::{Logger, warn};
+
+use crate::error::MuppetError;
+use crate::mdata::MdataProvider;
 
 #[derive(Serialize, Deserialize)]
-struct MantaDomain(pub String);
+pub struct MantaDomain(pub String);
+
+// Registrar stores a service under its domain name reversed into a path,
+// e.g. "1.moray.us-east.joyent.us" lives at "/us/joyent/us-east/moray/1".
+pub fn domain_to_zk_path(domain: &MantaDomain) -> String {
+    let labels: Vec<&str> = domain.0.split('.').rev().collect();
+
+    format!("/{}", labels.join("/"))
+}
 
 #[derive(Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct Config {
     name: MantaDomain,
-    trustedIP: Ipv4Addr,
-    adminIPs: Option<Vec<Ipv4Addr>>,
-    mantaIPs: Option<Vec<Ipv4Addr>>,
-    untrustedIPs: Option<Vec<Ipv4Addr>>,
-    zookeeper: ZookeeperConfig
+    trustedIP: IpAddr,
+    adminIPs: Option<Vec<IpAddr>>,
+    mantaIPs: Option<Vec<IpAddr>>,
+    untrustedIPs: Option<Vec<IpAddr>>,
+    strictNicParsing: Option<bool>,
+    excludedNicTags: Option<HashSet<String>>,
+    zookeeper: ZookeeperConfig,
+    haproxy: HaproxyConfig,
+    mdata: Option<MdataConfig>
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), MuppetError> {
+        // An empty name would have muppet watching the root of ZooKeeper.
+        if self.name.0.trim().is_empty() {
+            return Err(MuppetError::ConfigParse(
+                "\"name\" must not be empty".to_string()));
+        }
+        // Nor should any label be, e.g. "a..b" or "foo.com.", which would
+        // turn into an empty path component.
+        let bad_label = |l: &str| {
+            l.is_empty() || l.chars().any(char::is_whitespace)
+        };
+        if self.name.0.split('.').any(bad_label) {
+            return Err(MuppetError::ConfigParse(
+                format!("\"name\" {:?} has an empty label or one containing \
+                         whitespace", self.name.0)));
+        }
+
+        // Catch typos like a pasted FQDN list before they turn into a
+        // pathological znode path.
+        let path = self.get_zk_path();
+        let depth = path.matches('/').count();
+        let max_depth = self.zookeeper.get_max_path_depth();
+        if depth > max_depth {
+            return Err(MuppetError::ConfigParse(
+                format!("\"name\" has {} labels, more than the maximum of {}",
+                        depth, max_depth)));
+        }
+        let max_length = self.zookeeper.get_max_path_length();
+        if path.len() > max_length {
+            return Err(MuppetError::ConfigParse(
+                format!("znode path {} is longer than the maximum of {}",
+                        path, max_length)));
+        }
+
+        self.zookeeper.validate()?;
+        self.haproxy.validate()
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name.0
+    }
+
+    pub fn get_zk_path(&self) -> String {
+        domain_to_zk_path(&self.name)
+    }
+
+    pub fn get_trusted_ip(&self) -> IpAddr {
+        self.trustedIP
+    }
+
+    pub fn get_admin_ips(&self) -> &[IpAddr] {
+        self.adminIPs.as_deref().unwrap_or(&[])
+    }
+
+    pub fn get_manta_ips(&self) -> &[IpAddr] {
+        self.mantaIPs.as_deref().unwrap_or(&[])
+    }
+
+    pub fn get_untrusted_ips(&self) -> &[IpAddr] {
+        self.untrustedIPs.as_deref().unwrap_or(&[])
+    }
+
+    // Why a NIC address should not be treated as untrusted, or None if it
+    // should be.
+    pub fn untrusted_exclusion(&self, ip: &IpAddr, nic_tag: &Option<String>)
+                               -> Option<String> {
+        let listed = |ips: &Option<Vec<IpAddr>>| {
+            ips.iter().flatten().any(|i| i == ip)
+        };
+
+        if *ip == self.get_trusted_ip() {
+            return Some("trusted ip".to_string());
+        }
+        if listed(&self.adminIPs) {
+            return Some("listed in adminIPs".to_string());
+        }
+        if listed(&self.mantaIPs) {
+            return Some("listed in mantaIPs".to_string());
+        }
+        match (&self.excludedNicTags, nic_tag) {
+            (Some(excluded), Some(tag)) if excluded.contains(tag) => {
+                Some(format!("nic_tag {} is excluded", tag))
+            },
+            _ => None
+        }
+    }
+
+    // Every NIC address that isn't the trusted IP or one of the configured
+    // admin or manta IPs, and isn't on a NIC with an excluded tag, is treated
+    // as untrusted, in addition to any untrusted IPs given in the config.
+    pub fn add_untrusted_ips(&mut self,
+                             nic_ips: &HashMap<IpAddr, Option<String>>) {
+        let mut untrusted: HashSet<IpAddr> =
+            self.untrustedIPs.iter().flatten().cloned().collect();
+        untrusted.extend(nic_ips
+                         .iter()
+                         .filter(|&(ip, tag)| {
+                             self.untrusted_exclusion(ip, tag).is_none()
+                         })
+                         .map(|(ip, _)| *ip));
+
+        let mut untrusted: Vec<IpAddr> = untrusted.into_iter().collect();
+        untrusted.sort();
+        self.untrustedIPs = Some(untrusted);
+    }
+
+    pub fn get_strict_nic_parsing(&self) -> bool {
+        self.strictNicParsing.unwrap_or(false)
+    }
+
+    pub fn populate_untrusted_ips<M>(&mut self, mdata: &M, log: &Logger)
+                                     -> Result<(), MuppetError>
+        where M: MdataProvider
+    {
+        let nics = mdata.get_nics()?;
+        let strict = self.get_strict_nic_parsing();
+        let nic_ips = parse_sdc_nics(&nics, strict, log)?;
+
+        self.add_untrusted_ips(&nic_ips);
+
+        Ok(())
+    }
+
+    pub fn get_mdata_bin(&self) -> &Path {
+        self.mdata.as_ref()
+            .and_then(|m| m.bin.as_deref())
+            .unwrap_or_else(|| Path::new(DEFAULT_MDATA_BIN))
+    }
+
+    pub fn get_mdata_key(&self) -> &str {
+        self.mdata.as_ref()
+            .and_then(|m| m.key.as_deref())
+            .unwrap_or(DEFAULT_MDATA_KEY)
+    }
+
+    pub fn get_zookeeper(&self) -> &ZookeeperConfig {
+        &self.zookeeper
+    }
+
+    pub fn get_haproxy(&self) -> &HaproxyConfig {
+        &self.haproxy
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
 pub struct ZookeeperConfig {
     servers: Vec<ZookeeperServer>,
-    timeout: u64
+    timeout: u64,
+    maxPathDepth: Option<usize>,
+    maxPathLength: Option<usize>
+}
+
+impl ZookeeperConfig {
+    // Catch configs that would only fail later, and cryptically, when
+    // connecting.
+    pub fn validate(&self) -> Result<(), MuppetError> {
+        if self.servers.is_empty() {
+            return Err(MuppetError::ConfigParse(
+                "no zookeeper servers configured".to_string()));
+        }
+        if self.timeout == 0 {
+            return Err(MuppetError::ConfigParse(
+                "zookeeper timeout must be greater than zero".to_string()));
+        }
+        if let Some(s) = self.servers.iter().find(|s| s.port == 0) {
+            return Err(MuppetError::ConfigParse(
+                format!("zookeeper server {} has no port", s.host)));
+        }
+
+        Ok(())
+    }
+
+    // The "host:port,host:port" form expected by ZooKeeper::connect. Empty
+    // if no servers are configured.
+    pub fn connection_string(&self) -> String {
+        self.server_connection_strings().join(",")
+    }
+
+    // A connection string for each server on its own, for talking to one
+    // ensemble member at a time.
+    pub fn server_connection_strings(&self) -> Vec<String> {
+        self.servers
+            .iter()
+            .map(|s| format!("{}:{}", s.host, s.port))
+            .collect()
+    }
+
+    pub fn get_server_count(&self) -> usize {
+        self.servers.len()
+    }
+
+    // Session timeout in milliseconds.
+    pub fn get_timeout(&self) -> u64 {
+        self.timeout
+    }
+
+    pub fn get_max_path_depth(&self) -> usize {
+        self.maxPathDepth.unwrap_or(DEFAULT_MAX_PATH_DEPTH)
+    }
+
+    pub fn get_max_path_length(&self) -> usize {
+        self.maxPathLength.unwrap_or(DEFAULT_MAX_PATH_LENGTH)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,12 +259,789 @@ pub struct ZookeeperServer {
     port: u32
 }
 
-pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Config, Box<Error>> {
+// Where to read the NIC metadata from. Defaults to `mdata-get sdc:nics`;
+// pointing `bin` at a script that prints fixture JSON allows running
+// outside of a Triton zone.
+#[derive(Serialize, Deserialize)]
+pub struct MdataConfig {
+    bin: Option<PathBuf>,
+    key: Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+#[allow(non_snake_case)]
+pub struct HaproxyConfig {
+    template: PathBuf,
+    configFile: PathBuf,
+    pidFile: PathBuf,
+    bin: Option<PathBuf>,
+    backendPort: u16,
+    reloadDebounceMs: Option<u64>,
+    shutdownSessionsOnDown: Option<bool>,
+    minBackendFraction: Option<f64>,
+    minBackendFractionSettleSecs: Option<u64>,
+    backendWarmupSecs: Option<u64>,
+    sslCaFile: Option<PathBuf>
+}
+
+impl HaproxyConfig {
+    pub fn validate(&self) -> Result<(), MuppetError> {
+        if let Some(fraction) = self.minBackendFraction {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(MuppetError::ConfigParse(
+                    format!("minBackendFraction {} is not between 0 and 1",
+                            fraction)));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn get_template(&self) -> &Path {
+        &self.template
+    }
+
+    pub fn get_config_file(&self) -> &Path {
+        &self.configFile
+    }
+
+    pub fn get_pid_file(&self) -> &Path {
+        &self.pidFile
+    }
+
+    pub fn get_bin(&self) -> &Path {
+        self.bin.as_deref()
+            .unwrap_or_else(|| Path::new("haproxy"))
+    }
+
+    pub fn get_backend_port(&self) -> u16 {
+        self.backendPort
+    }
+
+    // Whether haproxy should kill a server's sessions as soon as it is
+    // marked down, rather than letting them linger.
+    pub fn get_shutdown_sessions_on_down(&self) -> bool {
+        self.shutdownSessionsOnDown.unwrap_or(false)
+    }
+
+    // If set, a new backend set smaller than this fraction of the last one
+    // applied is refused, on the basis that it more likely reflects a bad
+    // read of ZooKeeper than a real loss of hosts.
+    pub fn get_min_backend_fraction(&self) -> Option<f64> {
+        self.minBackendFraction
+    }
+
+    // If set, how long a set refused for being too small must stay the same
+    // size before it is accepted as the new normal. Unset, such a set is
+    // refused for as long as it lasts.
+    pub fn get_min_backend_fraction_settle(&self) -> Option<Duration> {
+        self.minBackendFractionSettleSecs.map(Duration::from_secs)
+    }
+
+    // How long backends must stay unchanged before a reload, so a rolling
+    // deploy results in one reload rather than a storm of them.
+    pub fn get_reload_debounce(&self) -> Duration {
+        Duration::from_millis(self.reloadDebounceMs
+                              .unwrap_or(DEFAULT_RELOAD_DEBOUNCE_MS))
+    }
+
+    // How long a newly-registered backend must stay registered before it is
+    // added to the config. Zero (the default) adds backends straight away.
+    pub fn get_backend_warmup(&self) -> Duration {
+        Duration::from_secs(self.backendWarmupSecs
+                            .unwrap_or(DEFAULT_BACKEND_WARMUP_SECS))
+    }
+
+    // CA bundle to verify https backends' certificates against. Without one
+    // their certificates go unverified.
+    pub fn get_ssl_ca_file(&self) -> Option<&Path> {
+        self.sslCaFile.as_deref()
+    }
+}
+
+// Each sdc:nics entry lists its addresses in CIDR form in "ips", while older
+// platforms only give a single "ip". Entries with neither are skipped with a
+// warning, or rejected if `strict` is set. Each address is returned with the
+// nic_tag of the NIC it was found on.
+pub fn parse_sdc_nics(json: &str, strict: bool, log: &Logger)
+                      -> Result<HashMap<IpAddr, Option<String>>, MuppetError> {
+    let nics = match serde_json::from_str::<Value>(json) {
+        Ok(Value::Array(nics)) => nics,
+        Ok(_) => {
+            return Err(MuppetError::NicParse(
+                "expected an array of nics".to_string()));
+        },
+        Err(e) => return Err(MuppetError::NicParse(e.to_string()))
+    };
+    let mut ips = HashMap::new();
+
+    for (i, nic) in nics.iter().enumerate() {
+        let nic_tag = nic.get("nic_tag")
+            .and_then(|v| v.as_str())
+            .map(|t| t.to_string());
+        let ip_strs: Vec<&str> = match (nic.get("ips"), nic.get("ip")) {
+            (Some(Value::Array(ips)), _) => {
+                ips.iter().filter_map(|v| v.as_str()).collect()
+            },
+            (_, Some(Value::String(ip))) => vec![ip.as_str()],
+            _ => {
+                if strict {
+                    return Err(MuppetError::NicParse(
+                        format!("nic {} has no \"ips\" or \"ip\"", i)));
+                }
+                warn!(log, "ignoring nic with unrecognized shape";
+                      "index" => i, "nic" => nic.to_string());
+                continue;
+            }
+        };
+
+        for ip_str in ip_strs {
+            // Strip the prefix length and any IPv6 zone id, e.g.
+            // "fe80::1%net0/64", neither of which IpAddr will parse.
+            let addr = ip_str.split('/').next().unwrap_or("");
+            let addr = addr.split('%').next().unwrap_or("");
+            match addr.parse::<IpAddr>() {
+                Ok(ip) => {
+                    ips.insert(ip, nic_tag.clone());
+                },
+                Err(e) => {
+                    if strict {
+                        return Err(MuppetError::NicParse(
+                            format!("invalid ip {}: {}", ip_str, e)));
+                    }
+                    warn!(log, "ignoring unparseable nic ip";
+                          "ip" => ip_str, "error" => e.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(ips)
+}
+
+static DEFAULT_MDATA_BIN: &str = "mdata-get";
+static DEFAULT_MDATA_KEY: &str = "sdc:nics";
+
+static DEFAULT_MAX_PATH_DEPTH: usize = 16;
+static DEFAULT_MAX_PATH_LENGTH: usize = 1024;
+
+static DEFAULT_RELOAD_DEBOUNCE_MS: u64 = 1000;
+static DEFAULT_BACKEND_WARMUP_SECS: u64 = 0;
+
+static SAMPLE_CONFIG: &str = r#"{
+    "_comment": "Sample muppet config. Replace the placeholder values below.",
+    "name": "1.moray.example.joyent.us",
+    "trustedIP": "127.0.0.1",
+    "adminIPs": [],
+    "mantaIPs": [],
+    "zookeeper": {
+        "servers": [
+            { "host": "127.0.0.1", "port": 2181 }
+        ],
+        "timeout": 1000
+    },
+    "haproxy": {
+        "template": "/opt/muppet/etc/haproxy.cfg.in",
+        "configFile": "/opt/muppet/etc/haproxy.cfg",
+        "pidFile": "/var/run/haproxy.pid",
+        "backendPort": 80
+    }
+}
+"#;
+
+static DEFAULT_PROFILE: &str = "default";
+
+static HTTP_TIMEOUT_SECS: u64 = 10;
+static MAX_REMOTE_CONFIG_BYTES: u64 = 1024 * 1024;
+
+// A config file may either be a single config or hold several named configs
+// under a top level "profiles" object, in which case the requested profile
+// (or "default") is selected.
+fn select_profile(value: Value, profile: Option<&str>)
+                  -> Result<Value, MuppetError> {
+    let mut map = match value {
+        Value::Object(map) => map,
+        other => return Ok(other)
+    };
+
+    match map.remove("profiles") {
+        Some(Value::Object(mut profiles)) => {
+            let name = profile.unwrap_or(DEFAULT_PROFILE);
+            profiles.remove(name).ok_or_else(|| MuppetError::ConfigParse(
+                format!("profile {} not found", name)))
+        },
+        Some(_) => Err(MuppetError::ConfigParse(
+            "\"profiles\" must be an object".to_string())),
+        None => match profile {
+            Some(name) => Err(MuppetError::ConfigParse(
+                format!("profile {} requested but there are no profiles",
+                        name))),
+            None => Ok(Value::Object(map))
+        }
+    }
+}
+
+pub fn read_source(source: &str, profile: Option<&str>)
+                   -> Result<Config, MuppetError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        read_url(source, profile)
+    } else {
+        read_file(source, profile)
+    }
+}
+
+pub fn read_url(url: &str, profile: Option<&str>)
+                -> Result<Config, MuppetError> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
+        .build()?;
+    let response = client.get(url).send()?;
+
+    if !response.status().is_success() {
+        return Err(MuppetError::ConfigParse(
+            format!("fetching {} returned {}", url, response.status())));
+    }
+
+    let content_type = response.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !content_type.starts_with("application/json") {
+        return Err(MuppetError::ConfigParse(
+            format!("unexpected content type from {}: {}", url,
+                    content_type)));
+    }
+
+    match response.content_length() {
+        Some(len) if len > MAX_REMOTE_CONFIG_BYTES => {
+            return Err(MuppetError::ConfigParse(
+                format!("{} is too large ({} bytes)", url, len)));
+        },
+        _ => ()
+    }
+
+    // Cap the read in case the server did not send a content length.
+    let mut contents = String::new();
+    response.take(MAX_REMOTE_CONFIG_BYTES).read_to_string(&mut contents)?;
+
+    read_str(&contents, profile)
+}
+
+pub fn read_file<P: AsRef<Path>>(path: P, profile: Option<&str>)
+                                 -> Result<Config, MuppetError> {
     let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    let mut reader = BufReader::new(file);
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents)?;
 
-    // Read the JSON contents of the file as an instance of `Config`.
-    let c = serde_json::from_reader(reader)?;
+    read_str(&contents, profile)
+}
+
+// Replaces each `${VAR}` in `s` with the value of the environment variable
+// VAR, or `${VAR:-default}` with `default` if VAR is unset.
+fn expand_env_str(s: &str) -> Result<String, MuppetError> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| {
+            MuppetError::ConfigParse("unterminated \"${\"".to_string())
+        })?;
+        let expr = &after[..end];
+        let (name, default) = match expr.find(":-") {
+            Some(i) => (&expr[..i], Some(&expr[i + 2..])),
+            None => (expr, None)
+        };
+
+        match (env::var(name), default) {
+            (Ok(value), _) => expanded.push_str(&value),
+            (Err(_), Some(default)) => expanded.push_str(default),
+            (Err(_), None) => {
+                return Err(MuppetError::ConfigParse(
+                    format!("environment variable {} is not set", name)));
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+// Expands environment variables in every string value of the parsed config.
+// Doing this after parsing means a value can't change the JSON's structure,
+// whatever characters it contains.
+fn expand_env(value: &mut Value) -> Result<(), MuppetError> {
+    match value {
+        Value::String(s) => *s = expand_env_str(s)?,
+        Value::Array(values) => {
+            for v in values {
+                expand_env(v)?;
+            }
+        },
+        Value::Object(map) => {
+            for (_, v) in map.iter_mut() {
+                expand_env(v)?;
+            }
+        },
+        _ => ()
+    }
+
+    Ok(())
+}
+
+pub fn read_str(contents: &str, profile: Option<&str>)
+                -> Result<Config, MuppetError> {
+    // serde_json rejects a leading byte order mark, which editors on some
+    // platforms like to add.
+    let contents = contents.trim_start_matches('\u{feff}');
+
+    // Read the JSON contents and pick out the `Config`. Only the selected
+    // profile is expanded, so another profile's variables needn't be set.
+    let v = serde_json::from_str(contents)?;
+    let mut v = select_profile(v, profile)?;
+    expand_env(&mut v)?;
+    let c: Config = serde_json::from_value(v)?;
+
+    c.validate()?;
 
     Ok(c)
 }
+
+// Refuses to overwrite an existing file.
+pub fn write_sample<P: AsRef<Path>>(path: P) -> Result<(), MuppetError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+
+    file.write_all(SAMPLE_CONFIG.as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::testutil::{discard_log, ip};
+
+    static TEST_CONFIG: &str = r#"{
+        "name": "1.moray.us-east.joyent.us",
+        "trustedIP": "10.0.0.1",
+        "adminIPs": ["10.1.0.1"],
+        "mantaIPs": ["10.2.0.1"],
+        "zookeeper": {
+            "servers": [{ "host": "10.3.0.1", "port": 2181 }],
+            "timeout": 1000
+        },
+        "haproxy": {
+            "template": "/opt/muppet/etc/haproxy.cfg.in",
+            "configFile": "/opt/muppet/etc/haproxy.cfg",
+            "pidFile": "/var/run/haproxy.pid",
+            "backendPort": 80
+        }
+    }"#;
+
+    // sdc:nics as mdata-get reports it, with a NIC on each of the admin and
+    // manta networks, a dual-stack external NIC, a NAT NIC, and one from an
+    // older platform with only "ip".
+    static MIX_SDC_NICS_TEST_DATA: &str =
+        include_str!("../tests/data/sdc-nics.json");
+
+    struct FakeMdata(&'static str);
+
+    impl MdataProvider for FakeMdata {
+        fn get_nics(&self) -> Result<String, MuppetError> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    fn test_config() -> Config {
+        read_str(TEST_CONFIG, None).unwrap()
+    }
+
+    #[test]
+    fn trusted_ip() {
+        assert_eq!(test_config().get_trusted_ip(), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn sample_config_parses() {
+        let c = read_str(SAMPLE_CONFIG, None).unwrap();
+        assert_eq!(c.get_name(), "1.moray.example.joyent.us");
+    }
+
+    #[test]
+    fn byte_order_mark_is_ignored() {
+        let with_bom = format!("\u{feff}{}", TEST_CONFIG);
+        let c = read_str(&with_bom, None).unwrap();
+        assert_eq!(c.get_name(), test_config().get_name());
+    }
+
+    fn with_name(name: &str) -> String {
+        TEST_CONFIG.replace("1.moray.us-east.joyent.us", name)
+    }
+
+    fn error_string(result: Result<Config, MuppetError>) -> String {
+        match result {
+            Ok(_) => panic!("expected config to be rejected"),
+            Err(e) => e.to_string()
+        }
+    }
+
+    fn parse_error(contents: &str) -> String {
+        error_string(read_str(contents, None))
+    }
+
+    #[test]
+    fn empty_name_rejected() {
+        assert!(parse_error(&with_name("")).contains("must not be empty"));
+        assert!(parse_error(&with_name("  ")).contains("must not be empty"));
+    }
+
+    #[test]
+    fn empty_or_blank_label_rejected() {
+        for name in &["a..b", "foo.com.", ".foo.com", " foo ", "foo. .com"] {
+            assert!(parse_error(&with_name(name)).contains("empty label"),
+                    "{:?} was not rejected", name);
+        }
+    }
+
+    fn zookeeper(hosts: &[&str]) -> ZookeeperConfig {
+        ZookeeperConfig {
+            servers: hosts.iter()
+                .map(|h| ZookeeperServer { host: h.to_string(), port: 2181 })
+                .collect(),
+            timeout: 1000,
+            maxPathDepth: None,
+            maxPathLength: None
+        }
+    }
+
+    #[test]
+    fn connection_string_one_server() {
+        assert_eq!(zookeeper(&["10.0.0.1"]).connection_string(),
+                   "10.0.0.1:2181");
+    }
+
+    #[test]
+    fn connection_string_three_servers() {
+        let zk = zookeeper(&["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        assert_eq!(zk.connection_string(),
+                   "10.0.0.1:2181,10.0.0.2:2181,10.0.0.3:2181");
+    }
+
+    #[test]
+    fn connection_string_no_servers() {
+        assert_eq!(zookeeper(&[]).connection_string(), "");
+    }
+
+    #[test]
+    fn zk_path_multi_label() {
+        let domain = MantaDomain("1.moray.us-east.joyent.us".to_string());
+        assert_eq!(domain_to_zk_path(&domain), "/us/joyent/us-east/moray/1");
+    }
+
+    #[test]
+    fn zk_path_single_label() {
+        let domain = MantaDomain("moray".to_string());
+        assert_eq!(domain_to_zk_path(&domain), "/moray");
+    }
+
+    #[test]
+    fn name() {
+        assert_eq!(test_config().get_name(), "1.moray.us-east.joyent.us");
+    }
+
+    // Answers a single request on a local port with `body`, returning the
+    // URL to fetch it from.
+    fn serve_once(content_type: &'static str, body: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n\
+                            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   content_type, body.len(), body).unwrap();
+        });
+
+        format!("http://{}/config.json", addr)
+    }
+
+    #[test]
+    fn read_url_json() {
+        let url = serve_once("application/json", TEST_CONFIG.to_string());
+        let c = read_source(&url, None).unwrap();
+        assert_eq!(c.get_name(), "1.moray.us-east.joyent.us");
+    }
+
+    #[test]
+    fn read_url_wrong_content_type() {
+        let url = serve_once("text/html", "<html></html>".to_string());
+        assert!(error_string(read_source(&url, None))
+                .contains("unexpected content type"));
+    }
+
+    #[test]
+    fn profiles() {
+        let contents = format!(r#"{{ "profiles": {{
+            "default": {},
+            "staging": {}
+        }} }}"#, TEST_CONFIG, with_name("1.moray.staging.joyent.us"));
+
+        let c = read_str(&contents, None).unwrap();
+        assert_eq!(c.get_name(), "1.moray.us-east.joyent.us");
+        let c = read_str(&contents, Some("staging")).unwrap();
+        assert_eq!(c.get_name(), "1.moray.staging.joyent.us");
+        assert!(error_string(read_str(&contents, Some("prod")))
+                .contains("profile prod not found"));
+    }
+
+    #[test]
+    fn unselected_profile_not_expanded() {
+        let contents = format!(r#"{{ "profiles": {{
+            "default": {},
+            "staging": {}
+        }} }}"#, TEST_CONFIG, with_name("${MUPPET_TEST_ENV_STAGING}"));
+
+        let c = read_str(&contents, None).unwrap();
+        assert_eq!(c.get_name(), "1.moray.us-east.joyent.us");
+        assert!(error_string(read_str(&contents, Some("staging")))
+                .contains("MUPPET_TEST_ENV_STAGING is not set"));
+    }
+
+    #[test]
+    fn profile_without_profiles() {
+        assert!(error_string(read_str(TEST_CONFIG, Some("staging")))
+                .contains("there are no profiles"));
+    }
+
+    #[test]
+    fn deep_name_rejected() {
+        let name = vec!["a"; 40].join(".");
+        assert!(parse_error(&with_name(&name))
+                .contains("has 40 labels, more than the maximum of 16"));
+    }
+
+    #[test]
+    fn long_name_rejected() {
+        let name = vec!["a".repeat(400); 3].join(".");
+        assert!(parse_error(&with_name(&name))
+                .contains("longer than the maximum of 1024"));
+    }
+
+    #[test]
+    fn path_bounds_configurable() {
+        let contents = TEST_CONFIG.replace(
+            r#""timeout": 1000"#,
+            r#""timeout": 1000, "maxPathDepth": 4, "maxPathLength": 16"#);
+        assert!(parse_error(&contents)
+                .contains("has 5 labels, more than the maximum of 4"));
+
+        let contents = with_name("a.b.c").replace(
+            r#""timeout": 1000"#, r#""timeout": 1000, "maxPathDepth": 4"#);
+        assert!(read_str(&contents, None).is_ok());
+    }
+
+    #[test]
+    fn populate_untrusted_from_mdata() {
+        let mut c = test_config();
+        c.populate_untrusted_ips(&FakeMdata(MIX_SDC_NICS_TEST_DATA),
+                                 &discard_log()).unwrap();
+
+        assert_eq!(c.get_untrusted_ips(),
+                   &[ip("165.225.1.10"), ip("172.16.0.5"),
+                     ip("192.168.128.5"), ip("2001:db8::10")][..]);
+    }
+
+    #[test]
+    fn populate_untrusted_keeps_configured() {
+        let contents = TEST_CONFIG.replace(
+            r#""mantaIPs""#, r#""untrustedIPs": ["10.9.0.1"], "mantaIPs""#);
+        let mut c = read_str(&contents, None).unwrap();
+        c.populate_untrusted_ips(&FakeMdata(r#"[{ "ip": "172.16.0.5" }]"#),
+                                 &discard_log()).unwrap();
+
+        assert_eq!(c.get_untrusted_ips(),
+                   &[ip("10.9.0.1"), ip("172.16.0.5")][..]);
+    }
+
+    static ODD_SDC_NICS: &str = r#"[
+        { "interface": "net0", "ips": ["172.16.0.5/24", "dhcp"] },
+        { "interface": "net1", "nic_tag": "external" },
+        { "interface": "net2", "ip": "172.16.1.5" }
+    ]"#;
+
+    #[test]
+    fn nics_recoverable_shapes_skipped() {
+        let ips = parse_sdc_nics(ODD_SDC_NICS, false, &discard_log())
+            .unwrap();
+
+        let mut found: Vec<IpAddr> = ips.keys().cloned().collect();
+        found.sort();
+        assert_eq!(found, vec![ip("172.16.0.5"), ip("172.16.1.5")]);
+    }
+
+    #[test]
+    fn nics_recoverable_shapes_strict() {
+        let err = parse_sdc_nics(ODD_SDC_NICS, true, &discard_log())
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid ip dhcp"));
+    }
+
+    #[test]
+    fn nics_not_an_array() {
+        assert!(parse_sdc_nics(r#"{ "ips": [] }"#, false, &discard_log())
+                .is_err());
+    }
+
+    #[test]
+    fn excluded_nic_tag_not_untrusted() {
+        let contents = TEST_CONFIG.replace(
+            r#""mantaIPs""#, r#""excludedNicTags": ["mantanat"], "mantaIPs""#);
+        let mut c = read_str(&contents, None).unwrap();
+        c.populate_untrusted_ips(&FakeMdata(MIX_SDC_NICS_TEST_DATA),
+                                 &discard_log()).unwrap();
+
+        assert_eq!(c.get_untrusted_ips(),
+                   &[ip("165.225.1.10"), ip("172.16.0.5"),
+                     ip("2001:db8::10")][..]);
+    }
+
+    #[test]
+    fn untrusted_exclusion_reasons() {
+        let contents = TEST_CONFIG.replace(
+            r#""mantaIPs""#, r#""excludedNicTags": ["mantanat"], "mantaIPs""#);
+        let c = read_str(&contents, None).unwrap();
+        let nics = parse_sdc_nics(MIX_SDC_NICS_TEST_DATA, false,
+                                  &discard_log()).unwrap();
+        let reason = |addr: &str| {
+            let addr = ip(addr);
+            c.untrusted_exclusion(&addr, &nics[&addr])
+        };
+
+        assert_eq!(c.untrusted_exclusion(&ip("10.0.0.1"), &None),
+                   Some("trusted ip".to_string()));
+        assert_eq!(reason("10.1.0.1"), Some("listed in adminIPs".to_string()));
+        assert_eq!(reason("10.2.0.1"), Some("listed in mantaIPs".to_string()));
+        assert_eq!(reason("192.168.128.5"),
+                   Some("nic_tag mantanat is excluded".to_string()));
+        assert_eq!(reason("165.225.1.10"), None);
+        assert_eq!(reason("172.16.0.5"), None);
+    }
+
+    #[test]
+    fn nics_ipv6() {
+        let nics = r#"[
+            { "nic_tag": "external", "ips": ["2001:db8::10/64"] },
+            { "nic_tag": "internal", "ips": ["fe80::1%net1/64"] }
+        ]"#;
+        let ips = parse_sdc_nics(nics, true, &discard_log()).unwrap();
+
+        assert_eq!(ips.get(&ip("2001:db8::10")),
+                   Some(&Some("external".to_string())));
+        assert_eq!(ips.get(&ip("fe80::1")),
+                   Some(&Some("internal".to_string())));
+        assert_eq!(ips.len(), 2);
+    }
+
+    fn with_min_fraction(fraction: &str) -> String {
+        TEST_CONFIG.replace(r#""backendPort": 80"#,
+                            &format!(r#""backendPort": 80,
+                                        "minBackendFraction": {}"#, fraction))
+    }
+
+    #[test]
+    fn min_backend_fraction_range() {
+        for fraction in &["0", "0.5", "1"] {
+            assert!(read_str(&with_min_fraction(fraction), None).is_ok());
+        }
+        for fraction in &["-0.1", "1.5"] {
+            assert!(parse_error(&with_min_fraction(fraction))
+                    .contains("not between 0 and 1"));
+        }
+
+        let mut c = test_config();
+        c.haproxy.minBackendFraction = Some(std::f64::NAN);
+        assert!(c.validate().is_err());
+    }
+
+    #[test]
+    fn env_substituted() {
+        env::set_var("MUPPET_TEST_ENV_NAME", "2.moray.us-east.joyent.us");
+        let c = read_str(&with_name("${MUPPET_TEST_ENV_NAME}"), None)
+            .unwrap();
+        assert_eq!(c.get_name(), "2.moray.us-east.joyent.us");
+
+        let c = read_str(&with_name("${MUPPET_TEST_ENV_UNSET:-3.moray.us}"),
+                         None).unwrap();
+        assert_eq!(c.get_name(), "3.moray.us");
+
+        assert!(parse_error(&with_name("${MUPPET_TEST_ENV_UNSET}"))
+                .contains("MUPPET_TEST_ENV_UNSET is not set"));
+        assert!(parse_error(&with_name("${MUPPET_TEST_ENV_NAME"))
+                .contains("unterminated"));
+    }
+
+    #[test]
+    fn env_value_cannot_break_json() {
+        let key = "MUPPET_TEST_ENV_QUOTED";
+        env::set_var(key, r#"/opt/muppet/"quoted", "pidFile": "/tmp/x"#);
+        let contents = TEST_CONFIG.replace("/opt/muppet/etc/haproxy.cfg.in",
+                                           &format!("${{{}}}", key));
+
+        let c = read_str(&contents, None).unwrap();
+        assert_eq!(c.get_haproxy().get_template(),
+                   Path::new(r#"/opt/muppet/"quoted", "pidFile": "/tmp/x"#));
+        assert_eq!(c.get_haproxy().get_pid_file(),
+                   Path::new("/var/run/haproxy.pid"));
+    }
+
+    fn zookeeper_error(zk: &ZookeeperConfig) -> String {
+        match zk.validate() {
+            Ok(()) => panic!("expected zookeeper config to be rejected"),
+            Err(e) => e.to_string()
+        }
+    }
+
+    #[test]
+    fn zookeeper_valid() {
+        assert!(zookeeper(&["10.0.0.1", "10.0.0.2"]).validate().is_ok());
+    }
+
+    #[test]
+    fn zookeeper_no_servers() {
+        assert!(zookeeper_error(&zookeeper(&[]))
+                .contains("no zookeeper servers"));
+    }
+
+    #[test]
+    fn zookeeper_zero_timeout() {
+        let mut zk = zookeeper(&["10.0.0.1"]);
+        zk.timeout = 0;
+        assert!(zookeeper_error(&zk).contains("timeout"));
+    }
+
+    #[test]
+    fn zookeeper_zero_port() {
+        let mut zk = zookeeper(&["10.0.0.1", "10.0.0.2"]);
+        zk.servers[1].port = 0;
+        assert!(zookeeper_error(&zk).contains("10.0.0.2 has no port"));
+    }
+}
@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::time::{Duration, Instant};
+
+// Coalesces a burst of updates into one: each update replaces any pending
+// value and pushes the deadline back, so only the latest value is released
+// once updates have been quiet for `quiet`. Time is passed in by the caller.
+pub struct Debouncer<T> {
+    quiet: Duration,
+    pending: Option<T>,
+    deadline: Option<Instant>
+}
+
+impl<T> Debouncer<T> {
+    pub fn new(quiet: Duration) -> Debouncer<T> {
+        Debouncer {
+            quiet,
+            pending: None,
+            deadline: None
+        }
+    }
+
+    pub fn update(&mut self, value: T, now: Instant) {
+        self.pending = Some(value);
+        self.deadline = Some(now + self.quiet);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn take_ready(&mut self, now: Instant) -> Option<T> {
+        match self.deadline {
+            Some(deadline) if now >= deadline => {
+                self.deadline = None;
+                self.pending.take()
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rapid_updates_release_once() {
+        let start = Instant::now();
+        let quiet = Duration::from_millis(1000);
+        let mut debouncer = Debouncer::new(quiet);
+        let mut released = Vec::new();
+
+        // Ten updates 100ms apart, each checked for release as it arrives.
+        for i in 0..10 {
+            let now = start + Duration::from_millis(i * 100);
+            debouncer.update(i, now);
+            released.extend(debouncer.take_ready(now));
+        }
+        let last = start + Duration::from_millis(900);
+        assert_eq!(debouncer.deadline(), Some(last + quiet));
+        released.extend(debouncer.take_ready(last + quiet));
+        released.extend(debouncer.take_ready(last + quiet * 2));
+
+        assert_eq!(released, vec![9]);
+    }
+
+    #[test]
+    fn nothing_pending() {
+        let mut debouncer: Debouncer<u32> =
+            Debouncer::new(Duration::from_millis(1000));
+        assert_eq!(debouncer.deadline(), None);
+        assert_eq!(debouncer.take_ready(Instant::now()), None);
+    }
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use zookeeper::ZkError;
+
+#[derive(Debug)]
+pub enum MuppetError {
+    ConfigParse(String),
+    MdataCommand(String),
+    NicParse(String),
+    Io(io::Error),
+    Zookeeper(String),
+    Haproxy(String),
+    Registrar(String)
+}
+
+impl fmt::Display for MuppetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MuppetError::ConfigParse(msg) => write!(f, "config error: {}", msg),
+            MuppetError::MdataCommand(msg) => write!(f, "mdata error: {}", msg),
+            MuppetError::NicParse(msg) => {
+                write!(f, "invalid sdc:nics: {}", msg)
+            },
+            MuppetError::Io(e) => write!(f, "{}", e),
+            MuppetError::Zookeeper(msg) => {
+                write!(f, "zookeeper error: {}", msg)
+            },
+            MuppetError::Haproxy(msg) => write!(f, "haproxy error: {}", msg),
+            MuppetError::Registrar(msg) => {
+                write!(f, "invalid registrar record: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for MuppetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MuppetError::Io(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for MuppetError {
+    fn from(e: io::Error) -> MuppetError {
+        MuppetError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for MuppetError {
+    fn from(e: serde_json::Error) -> MuppetError {
+        MuppetError::ConfigParse(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for MuppetError {
+    fn from(e: reqwest::Error) -> MuppetError {
+        MuppetError::ConfigParse(e.to_string())
+    }
+}
+
+impl From<ZkError> for MuppetError {
+    fn from(e: ZkError) -> MuppetError {
+        MuppetError::Zookeeper(format!("{:?}", e))
+    }
+}
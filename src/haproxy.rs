@@ -0,0 +1,497 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Command;
+
+use slog::{Logger, debug, info};
+
+use crate::config::HaproxyConfig;
+use crate::error::MuppetError;
+use crate::registrar::HostRecord;
+
+static SERVERS_PLACEHOLDER: &str = "{{servers}}";
+
+// Reads the haproxy config template. A template without the servers
+// placeholder would render every config with no backends at all, which
+// `haproxy -c` happily accepts, so it is rejected here instead.
+pub fn read_template(path: &Path) -> Result<String, MuppetError> {
+    let template = fs::read_to_string(path)?;
+
+    if !template.contains(SERVERS_PLACEHOLDER) {
+        return Err(MuppetError::ConfigParse(
+            format!("haproxy template {} has no {} placeholder",
+                    path.display(), SERVERS_PLACEHOLDER)));
+    }
+
+    Ok(template)
+}
+
+// Replaces the servers placeholder in `template` with one server line per
+// backend. Servers are numbered in sorted address order so the same set of
+// backends always renders the same config, whatever order ZooKeeper lists
+// them in. A host registered more than once gets a single server line,
+// preferring a plain http record (see `conflicting_addresses`). Backends
+// registered with an https scheme are connected to over TLS, verified against
+// the configured CA file if there is one; haproxy 1.8 and later refuse a bare
+// "ssl" server.
+pub fn render_config(backends: &[HostRecord], c: &HaproxyConfig,
+                     template: &str) -> String {
+    let mut sorted: Vec<&HostRecord> = backends.iter().collect();
+    sorted.sort_by_key(|h| (h.address, h.is_https()));
+    sorted.dedup_by_key(|h| h.address);
+
+    let port = c.get_backend_port();
+    let mut options = String::from("check");
+    if c.get_shutdown_sessions_on_down() {
+        options.push_str(" on-marked-down shutdown-sessions");
+    }
+    let ssl = match c.get_ssl_ca_file() {
+        Some(ca_file) => {
+            format!(" ssl verify required ca-file {}", ca_file.display())
+        },
+        None => String::from(" ssl verify none")
+    };
+
+    let servers: Vec<String> = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, h)| format!("    server be_{} {}:{} {}{}",
+                              i, h.address, port, options,
+                              if h.is_https() { ssl.as_str() } else { "" }))
+        .collect();
+
+    template.replace(SERVERS_PLACEHOLDER, &servers.join("\n"))
+}
+
+// Addresses registered more than once with different schemes, of which
+// render_config can only honour one.
+pub fn conflicting_addresses(backends: &[HostRecord]) -> Vec<IpAddr> {
+    let mut schemes: BTreeMap<IpAddr, (bool, bool)> = BTreeMap::new();
+    for h in backends {
+        let seen = schemes.entry(h.address).or_insert((false, false));
+        if h.is_https() {
+            seen.1 = true;
+        } else {
+            seen.0 = true;
+        }
+    }
+
+    schemes.into_iter()
+        .filter(|&(_, (http, https))| http && https)
+        .map(|(address, _)| address)
+        .collect()
+}
+
+// Writes to a temporary file next to `path` and renames it into place so
+// haproxy never sees a partially written config. An existing file's
+// permissions are carried over.
+pub fn write_config_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                      "config path has no file name"))?;
+    let tmp_path = path.with_file_name(
+        format!(".{}.tmp", file_name.to_string_lossy()));
+
+    let result = File::create(&tmp_path).and_then(|mut f| {
+        f.write_all(contents.as_bytes())?;
+        f.sync_all()?;
+        if let Ok(metadata) = fs::metadata(path) {
+            f.set_permissions(metadata.permissions())?;
+        }
+        fs::rename(&tmp_path, path)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+fn read_pid(pid_file: &Path) -> Result<libc::pid_t, MuppetError> {
+    let contents = fs::read_to_string(pid_file)
+        .map_err(|e| MuppetError::Haproxy(
+            format!("failed to read pid file {}: {}", pid_file.display(), e)))?;
+    let pid = contents.trim().parse::<libc::pid_t>()
+        .map_err(|e| MuppetError::Haproxy(
+            format!("invalid pid in {}: {}", pid_file.display(), e)))?;
+
+    // kill(2) takes 0 and negative pids to mean whole process groups (-1
+    // being every process we may signal), whose members SIGUSR2 would kill.
+    if pid <= 0 {
+        return Err(MuppetError::Haproxy(
+            format!("invalid pid in {}: {}", pid_file.display(), pid)));
+    }
+
+    Ok(pid)
+}
+
+// Runs `haproxy -c` against the config, returning haproxy's diagnostics if
+// it rejects it.
+pub fn check_config(config_path: &Path, haproxy_bin: &Path)
+                    -> Result<(), String> {
+    let output = Command::new(haproxy_bin)
+        .arg("-c")
+        .arg("-f")
+        .arg(config_path)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}",
+                             haproxy_bin.display(), e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+    }
+
+    Ok(())
+}
+
+// Sends SIGUSR2 to the haproxy master process, which has it gracefully
+// reload its configuration.
+pub fn reload(pid_file: &Path, log: &Logger) -> Result<(), MuppetError> {
+    let pid = read_pid(pid_file)?;
+
+    info!(log, "reloading haproxy"; "pid" => pid, "signal" => "SIGUSR2");
+
+    if unsafe { libc::kill(pid, libc::SIGUSR2) } != 0 {
+        return Err(MuppetError::Haproxy(
+            format!("failed to signal pid {}: {}", pid,
+                    io::Error::last_os_error())));
+    }
+
+    Ok(())
+}
+
+// Installs rendered configs and reloads haproxy with them, skipping a render
+// identical to the last config haproxy was successfully reloaded with. That,
+// rather than the file on disk, is what's compared so that a config whose
+// reload failed is tried again in full next time, and so the first render
+// after startup always reloads.
+#[derive(Default)]
+pub struct Applier {
+    reloaded: Option<String>
+}
+
+impl Applier {
+    // The new config is checked with `haproxy -c` before it replaces the old
+    // one, so a bad render leaves both the file and the running haproxy
+    // untouched. Returns whether a reload was performed.
+    pub fn apply_if_changed(&mut self, c: &HaproxyConfig, contents: &str,
+                            log: &Logger) -> Result<bool, MuppetError> {
+        if self.reloaded.as_deref() == Some(contents) {
+            debug!(log, "config unchanged, skipping reload");
+            return Ok(false);
+        }
+
+        let path = c.get_config_file();
+        let file_name = path.file_name()
+            .ok_or_else(|| MuppetError::Haproxy(
+                format!("config path {} has no file name", path.display())))?;
+        let staged = path.with_file_name(
+            format!(".{}.check", file_name.to_string_lossy()));
+        fs::write(&staged, contents)?;
+        let checked = check_config(&staged, c.get_bin());
+        let _ = fs::remove_file(&staged);
+        checked.map_err(|e| MuppetError::Haproxy(
+            format!("rejected new config: {}", e)))?;
+
+        write_config_atomic(path, contents)?;
+        reload(c.get_pid_file(), log)?;
+        self.reloaded = Some(contents.to_string());
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use crate::registrar::Scheme;
+    use crate::testutil::{discard_log, fake_command, temp_path};
+
+    static TEMPLATE: &str = "backend be\n{{servers}}\n";
+
+    // Set to rewrite the golden file from the current render instead of
+    // comparing against it.
+    static REGEN_GOLDEN_VAR: &str = "MUPPET_REGEN_GOLDEN";
+
+    // A config with the required fields plus `extra`, which should start with
+    // a comma if given.
+    fn haproxy_config(extra: &str) -> HaproxyConfig {
+        serde_json::from_str(&format!(r#"{{
+            "template": "/opt/muppet/etc/haproxy.cfg.in",
+            "configFile": "/opt/muppet/etc/haproxy.cfg",
+            "pidFile": "/var/run/haproxy.pid",
+            "backendPort": 80{}
+        }}"#, extra)).unwrap()
+    }
+
+    fn host(address: &str, scheme: Option<Scheme>) -> HostRecord {
+        HostRecord {
+            address: address.parse().unwrap(),
+            scheme
+        }
+    }
+
+    #[test]
+    fn render_server_lines() {
+        let backends = vec![host("10.0.0.2", None), host("10.0.0.1", None)];
+        assert_eq!(render_config(&backends, &haproxy_config(""), TEMPLATE),
+                   "backend be\n\
+                    \x20   server be_0 10.0.0.1:80 check\n\
+                    \x20   server be_1 10.0.0.2:80 check\n");
+    }
+
+    #[test]
+    fn render_no_backends() {
+        assert_eq!(render_config(&[], &haproxy_config(""), TEMPLATE),
+                   "backend be\n\n");
+    }
+
+    #[test]
+    fn render_shutdown_sessions() {
+        let backends = vec![host("10.0.0.1", None)];
+        let off = haproxy_config(r#", "shutdownSessionsOnDown": false"#);
+        let on = haproxy_config(r#", "shutdownSessionsOnDown": true"#);
+
+        assert_eq!(render_config(&backends, &off, "{{servers}}"),
+                   "    server be_0 10.0.0.1:80 check");
+        assert_eq!(render_config(&backends, &on, "{{servers}}"),
+                   "    server be_0 10.0.0.1:80 check \
+                    on-marked-down shutdown-sessions");
+    }
+
+    #[test]
+    fn render_mixed_schemes() {
+        let backends = vec![
+            host("10.0.0.1", Some(Scheme::Http)),
+            host("10.0.0.2", Some(Scheme::Https))
+        ];
+        let with_ca = haproxy_config(
+            r#", "sslCaFile": "/opt/muppet/etc/ca.pem""#);
+
+        assert_eq!(render_config(&backends, &haproxy_config(""),
+                                 "{{servers}}"),
+                   "    server be_0 10.0.0.1:80 check\n\
+                    \x20   server be_1 10.0.0.2:80 check ssl verify none");
+        assert_eq!(render_config(&backends, &with_ca, "{{servers}}"),
+                   "    server be_0 10.0.0.1:80 check\n\
+                    \x20   server be_1 10.0.0.2:80 check \
+                    ssl verify required ca-file /opt/muppet/etc/ca.pem");
+    }
+
+    fn read_pid_from(name: &str, contents: &str)
+                     -> Result<libc::pid_t, MuppetError> {
+        let path = temp_path(name);
+        fs::write(&path, contents).unwrap();
+        let pid = read_pid(&path);
+        let _ = fs::remove_file(&path);
+        pid
+    }
+
+    #[test]
+    fn pid_file() {
+        assert_eq!(read_pid_from("pid-ok", "4242\n").unwrap(), 4242);
+    }
+
+    #[test]
+    fn pid_file_invalid() {
+        assert!(read_pid_from("pid-text", "haproxy\n").is_err());
+        assert!(read_pid_from("pid-empty", "").is_err());
+        assert!(read_pid_from("pid-zero", "0\n").is_err());
+        assert!(read_pid_from("pid-negative", "-1\n").is_err());
+    }
+
+    #[test]
+    fn pid_file_missing() {
+        assert!(read_pid(&temp_path("pid-missing")).is_err());
+    }
+
+    #[test]
+    fn check_config_rejected() {
+        let bin = fake_command(
+            "haproxy-reject",
+            "echo \"[ALERT] parsing [$3:1]: unknown keyword 'bogus'\" >&2\n\
+             exit 1");
+        let config = temp_path("rejected.cfg");
+        fs::write(&config, "bogus\n").unwrap();
+
+        let result = check_config(&config, &bin);
+        let _ = fs::remove_file(&bin);
+        let _ = fs::remove_file(&config);
+        let err = result.unwrap_err();
+        assert!(err.contains("unknown keyword 'bogus'"), "{}", err);
+    }
+
+    #[test]
+    fn check_config_accepted() {
+        let config = temp_path("accepted.cfg");
+        fs::write(&config, "global\n").unwrap();
+
+        let result = check_config(&config, Path::new("true"));
+        let _ = fs::remove_file(&config);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn write_atomic_replaces_file() {
+        let path = temp_path("atomic.cfg");
+        fs::write(&path, "old contents\n").unwrap();
+
+        write_config_atomic(&path, "new contents\n").unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let leftover = path.with_file_name(
+            format!(".{}.tmp", path.file_name().unwrap().to_string_lossy()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(contents, "new contents\n");
+        assert!(!leftover.exists());
+    }
+
+    // A config installed to and reloaded from temp files, checked with
+    // `true` in place of haproxy.
+    fn apply_config(name: &str) -> HaproxyConfig {
+        serde_json::from_str(&format!(r#"{{
+            "template": "/opt/muppet/etc/haproxy.cfg.in",
+            "configFile": "{}",
+            "pidFile": "{}",
+            "bin": "true",
+            "backendPort": 80
+        }}"#, temp_path(&format!("{}.cfg", name)).display(),
+           temp_path(&format!("{}.pid", name)).display())).unwrap()
+    }
+
+    #[test]
+    fn apply_changed_and_unchanged() {
+        let c = apply_config("apply");
+        let log = discard_log();
+        // Stands in for the haproxy master. SIGUSR2 kills it, but it only
+        // has to exist to be signalled.
+        let mut master = process::Command::new("sleep").arg("30").spawn()
+            .unwrap();
+        fs::write(c.get_pid_file(), master.id().to_string()).unwrap();
+        let mut applier = Applier::default();
+
+        let first = applier.apply_if_changed(&c, "backend one\n", &log);
+        let unchanged = applier.apply_if_changed(&c, "backend one\n", &log);
+        let changed = applier.apply_if_changed(&c, "backend two\n", &log);
+        let written = fs::read_to_string(c.get_config_file());
+
+        let _ = master.kill();
+        let _ = master.wait();
+        let _ = fs::remove_file(c.get_pid_file());
+        let _ = fs::remove_file(c.get_config_file());
+        assert!(first.unwrap());
+        assert!(!unchanged.unwrap());
+        assert!(changed.unwrap());
+        assert_eq!(written.unwrap(), "backend two\n");
+    }
+
+    #[test]
+    fn apply_retried_after_failed_reload() {
+        // With no pid file the reload fails after the config is written.
+        let c = apply_config("retry");
+        let log = discard_log();
+        let mut applier = Applier::default();
+
+        let first = applier.apply_if_changed(&c, "backend one\n", &log);
+        let second = applier.apply_if_changed(&c, "backend one\n", &log);
+
+        let _ = fs::remove_file(c.get_config_file());
+        assert!(first.is_err());
+        assert!(second.is_err());
+    }
+
+    // Locks down the full rendered output. After an intended change to it,
+    // regenerate the golden file with:
+    //
+    //     MUPPET_REGEN_GOLDEN=1 cargo test render_golden
+    //
+    // and review the diff.
+    #[test]
+    fn render_golden() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let template = fs::read_to_string(data.join("haproxy.cfg.in"))
+            .unwrap();
+        let golden_path = data.join("haproxy.cfg.golden");
+        let c = haproxy_config(r#", "shutdownSessionsOnDown": true"#);
+        let backends = vec![
+            host("10.0.0.2", Some(Scheme::Http)),
+            host("10.0.0.3", Some(Scheme::Https)),
+            host("10.0.0.1", None)
+        ];
+
+        let rendered = render_config(&backends, &c, &template);
+
+        if env::var_os(REGEN_GOLDEN_VAR).is_some() {
+            fs::write(&golden_path, &rendered).unwrap();
+        }
+        assert_eq!(rendered, fs::read_to_string(&golden_path).unwrap());
+    }
+
+    #[test]
+    fn render_order_independent() {
+        let hosts = vec![
+            host("10.0.0.3", Some(Scheme::Https)),
+            host("10.0.0.1", None),
+            host("10.0.0.2", Some(Scheme::Https)),
+            host("10.0.0.2", Some(Scheme::Http)),
+            host("10.0.0.1", None)
+        ];
+        let c = haproxy_config("");
+        let expected = render_config(&hosts, &c, TEMPLATE);
+
+        let mut reversed = hosts.clone();
+        reversed.reverse();
+        assert_eq!(render_config(&reversed, &c, TEMPLATE), expected);
+        for i in 0..hosts.len() {
+            let mut rotated = hosts.clone();
+            rotated.rotate_left(i);
+            assert_eq!(render_config(&rotated, &c, TEMPLATE), expected);
+        }
+
+        assert_eq!(expected,
+                   "backend be\n\
+                    \x20   server be_0 10.0.0.1:80 check\n\
+                    \x20   server be_1 10.0.0.2:80 check\n\
+                    \x20   server be_2 10.0.0.3:80 check ssl verify none\n");
+    }
+
+    #[test]
+    fn conflicting_schemes() {
+        let hosts = vec![
+            host("10.0.0.1", None),
+            host("10.0.0.1", Some(Scheme::Http)),
+            host("10.0.0.2", Some(Scheme::Https)),
+            host("10.0.0.2", None),
+            host("10.0.0.3", Some(Scheme::Https)),
+            host("10.0.0.3", Some(Scheme::Https))
+        ];
+
+        assert_eq!(conflicting_addresses(&hosts),
+                   vec!["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert!(conflicting_addresses(&[]).is_empty());
+    }
+
+    #[test]
+    fn template_needs_servers_placeholder() {
+        let path = temp_path("template.cfg.in");
+
+        fs::write(&path, TEMPLATE).unwrap();
+        assert_eq!(read_template(&path).unwrap(), TEMPLATE);
+
+        fs::write(&path, "backend be\n").unwrap();
+        let err = read_template(&path).unwrap_err().to_string();
+        let _ = fs::remove_file(&path);
+        assert!(err.contains("has no {{servers}} placeholder"));
+    }
+}
@@ -0,0 +1,145 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::mem;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use slog::{Drain, Level, Logger, OwnedKVList, Record, info};
+
+// A log level that can be changed while muppet runs, so debug logging can be
+// turned on during an incident and off again afterwards without a restart.
+#[derive(Clone)]
+pub struct LevelSwitch(Arc<AtomicUsize>);
+
+impl LevelSwitch {
+    pub fn new(level: Level) -> LevelSwitch {
+        LevelSwitch(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed))
+            .unwrap_or(Level::Info)
+    }
+
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+
+    // Wraps `drain` so that only records at the switch's current level or
+    // above get through.
+    pub fn filter<D: Drain>(&self, drain: D) -> SwitchedDrain<D> {
+        SwitchedDrain {
+            drain,
+            switch: self.clone()
+        }
+    }
+}
+
+pub struct SwitchedDrain<D> {
+    drain: D,
+    switch: LevelSwitch
+}
+
+impl<D: Drain> Drain for SwitchedDrain<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList)
+           -> Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.switch.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// One step more verbose than `level`, stopping at trace.
+pub fn more_verbose(level: Level) -> Level {
+    Level::from_usize(level.as_usize() + 1).unwrap_or(Level::Trace)
+}
+
+// Blocks SIGUSR1 and SIGUSR2 so they can be waited for by
+// `listen_for_signals`. This has to happen before any other thread is
+// started, as threads inherit the mask of the thread that starts them.
+pub fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+        set
+    }
+}
+
+// Waits on a thread of its own for the signals in `set`: SIGUSR1 makes
+// logging one step more verbose, and SIGUSR2 returns it to `initial`.
+pub fn listen_for_signals(set: libc::sigset_t, switch: LevelSwitch,
+                          initial: Level, log: Logger) {
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            continue;
+        }
+
+        let level = if signal == libc::SIGUSR1 {
+            more_verbose(switch.get())
+        } else {
+            initial
+        };
+        switch.set(level);
+        info!(log, "log level changed"; "level" => level.as_str());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use slog::{debug, o};
+
+    // Counts the records that reach it.
+    struct Count(Arc<AtomicUsize>);
+
+    impl Drain for Count {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, _: &Record, _: &OwnedKVList) -> Result<(), slog::Never> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn switch_changes_what_is_logged() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let switch = LevelSwitch::new(Level::Info);
+        let log = Logger::root(
+            switch.filter(Count(Arc::clone(&count))).fuse(), o!());
+
+        debug!(log, "hidden");
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        switch.set(Level::Debug);
+        debug!(log, "shown");
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+
+        switch.set(Level::Info);
+        debug!(log, "hidden again");
+        info!(log, "shown");
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn verbosity_steps() {
+        assert_eq!(more_verbose(Level::Info), Level::Debug);
+        assert_eq!(more_verbose(Level::Debug), Level::Trace);
+        assert_eq!(more_verbose(Level::Trace), Level::Trace);
+    }
+}
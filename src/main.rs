@@ -2,75 +2,888 @@
  * Copyright (c) 2019, Joyent, Inc.
  */
 
+mod backoff;
 mod config;
+mod debounce;
+mod error;
+mod haproxy;
+mod loglevel;
+mod mdata;
 mod opts;
+mod ratelimit;
+mod registrar;
+mod shrink;
+#[cfg(test)]
+mod testutil;
+mod warmup;
 
-use std::env;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use::clap::{crate_version, value_t};
-use slog::{Drain, Logger, info, o};
-use zookeeper::{ZkResult, ZooKeeper};
+use::clap::crate_version;
+use slog::{Drain, Level, Logger, debug, error, info, o, warn};
+use zookeeper::{KeeperState, WatchedEvent, ZkResult, ZooKeeper};
+use backoff::Backoff;
 use config::Config;
+use debounce::Debouncer;
+use error::MuppetError;
+use haproxy::Applier;
+use loglevel::LevelSwitch;
+use mdata::{CommandMdata, MdataProvider};
+use ratelimit::RateLimiter;
+use registrar::HostRecord;
+use shrink::ShrinkGuard;
+use warmup::Warmup;
 
-static APP: &'static str = "muppet";
+static APP: &str = "muppet";
 
+// At most this many "watch fired" lines are logged per interval, so a deploy
+// touching many hosts doesn't flood the logs.
+static WATCH_LOG_LIMIT: u32 = 10;
+static WATCH_LOG_INTERVAL_SECS: u64 = 60;
 
-fn get_untrusted_ips(c: &Config) -> Vec<Ipv4Addr> {
-    std::unimplemented!();
+static ZK_BACKOFF_BASE_MS: u64 = 500;
+static ZK_BACKOFF_MAX_SECS: u64 = 30;
+
+// Process exit codes for each category of failure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ExitCode {
+    Config = 2,
+    Mdata = 3,
+    Zookeeper = 4,
+    Io = 5,
+    Inconsistent = 6
 }
 
-fn zookeeper_session(c: &Config, uips: Vec<Ipv4Addr>) -> ZkResult<ZooKeeper> {
-    std::unimplemented!();
+impl ExitCode {
+    fn code(self) -> i32 {
+        self as i32
+    }
 }
 
-fn start_watch(z: &ZooKeeper, c: &Config) {
-    std::unimplemented!();
+fn zookeeper_session<W>(c: &Config, log: &Logger, watcher: W)
+                        -> Result<ZooKeeper, MuppetError>
+    where W: Fn(WatchedEvent) + Send + 'static
+{
+    let connect_string = c.get_zookeeper().connection_string();
+    let timeout = Duration::from_millis(c.get_zookeeper().get_timeout());
+
+    info!(log, "connecting to zookeeper"; "servers" => &connect_string);
+
+    let zk = ZooKeeper::connect(&connect_string, timeout, watcher)?;
+
+    Ok(zk)
 }
 
-fn main() {
+// Logs session state transitions, calling `on_expired` when the session has
+// expired and a new one (with new watches) is needed.
+fn handle_session_event<F>(event: &WatchedEvent, log: &Logger, on_expired: F)
+    where F: Fn()
+{
+    match event.keeper_state {
+        KeeperState::Expired => {
+            warn!(log, "zookeeper session expired"; "state" => "Expired");
+            on_expired();
+        },
+        KeeperState::Disconnected => {
+            warn!(log, "zookeeper disconnected"; "state" => "Disconnected");
+        },
+        KeeperState::SyncConnected => {
+            info!(log, "zookeeper connected"; "state" => "SyncConnected");
+        },
+        ref state => {
+            info!(log, "zookeeper session state changed";
+                  "state" => format!("{:?}", state));
+        }
+    }
+}
 
-    let matches = opts::parse(APP.to_string());
+fn zk_backoff() -> Backoff {
+    Backoff::new(Duration::from_millis(ZK_BACKOFF_BASE_MS),
+                 Duration::from_secs(ZK_BACKOFF_MAX_SECS))
+}
+
+// Keeps trying to establish a session, backing off between attempts. While
+// this goes on haproxy keeps running with whatever config it last had.
+// An expired session is reported on `expired_tx`.
+fn connect_with_backoff(c: &Config, log: &Logger, expired_tx: &Sender<()>)
+                        -> ZooKeeper {
+    let mut backoff = zk_backoff();
+    let mut attempt = 1;
+
+    loop {
+        let session_tx = expired_tx.clone();
+        let session_log = log.clone();
+        let watcher = move |event: WatchedEvent| {
+            handle_session_event(&event, &session_log, || {
+                let _ = session_tx.send(());
+            });
+        };
+
+        match zookeeper_session(c, log, watcher) {
+            Ok(zk) => return zk,
+            Err(e) => {
+                let delay = backoff::with_jitter(backoff.next_delay());
+                warn!(log, "failed to connect to zookeeper, retrying";
+                      "attempt" => attempt, "error" => e.to_string(),
+                      "delay_ms" => delay.as_secs() * 1000 +
+                                    u64::from(delay.subsec_millis()));
+                thread::sleep(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Reads the children of `path` and hands them to `on_change`, leaving a
+// watch behind that does the same again (re-arming itself) each time it fires.
+// If the watch can't be re-armed `on_lost` is called, as nothing more will be
+// heard about the service on this session.
+fn watch_children<F, L>(zk: Arc<ZooKeeper>, path: String, log: Logger,
+                        limiter: Arc<Mutex<RateLimiter>>, on_change: Arc<F>,
+                        on_lost: Arc<L>) -> ZkResult<()>
+    where F: Fn(Vec<String>) + Send + Sync + 'static,
+          L: Fn() + Send + Sync + 'static
+{
+    let watch_zk = Arc::clone(&zk);
+    let watch_path = path.clone();
+    let watch_log = log.clone();
+    let watch_limiter = Arc::clone(&limiter);
+    let watch_on_change = Arc::clone(&on_change);
+    let watch_on_lost = Arc::clone(&on_lost);
+
+    let children = zk.get_children_w(&path, move |event: WatchedEvent| {
+        let allowed = watch_limiter.lock().unwrap().allow(Instant::now());
+        if let Some(suppressed) = allowed {
+            debug!(watch_log, "watch fired";
+                   "event" => format!("{:?}", event.event_type),
+                   "suppressed" => suppressed);
+        }
+        let result = watch_children(watch_zk, watch_path, watch_log.clone(),
+                                    watch_limiter, watch_on_change,
+                                    Arc::clone(&watch_on_lost));
+        if let Err(e) = result {
+            error!(watch_log, "failed to re-arm watch";
+                   "error" => format!("{:?}", e));
+            watch_on_lost();
+        }
+    })?;
+
+    on_change(children);
+
+    Ok(())
+}
+
+fn start_watch<F, L>(zk: Arc<ZooKeeper>, c: &Config, log: &Logger,
+                     on_change: F, on_lost: L) -> Result<(), MuppetError>
+    where F: Fn(Vec<String>) + Send + Sync + 'static,
+          L: Fn() + Send + Sync + 'static
+{
+    // Config validation should already have caught this, but never fall
+    // back to watching the root of ZooKeeper.
+    if c.get_name().trim().is_empty() {
+        return Err(MuppetError::Zookeeper(
+            "refusing to watch with an empty service name".to_string()));
+    }
+
+    let path = c.get_zk_path();
+    info!(log, "watching service"; "path" => &path);
+
+    let limiter = RateLimiter::new(
+        WATCH_LOG_LIMIT, Duration::from_secs(WATCH_LOG_INTERVAL_SECS));
+
+    watch_children(zk, path, log.clone(), Arc::new(Mutex::new(limiter)),
+                   Arc::new(on_change), Arc::new(on_lost))?;
+
+    Ok(())
+}
+
+fn read_hosts(zk: &ZooKeeper, path: &str, children: &[String], log: &Logger)
+              -> Vec<HostRecord> {
+    let mut hosts = Vec::new();
+
+    for child in children {
+        let child_path = format!("{}/{}", path, child);
+        let parsed = zk.get_data(&child_path, false)
+            .map_err(MuppetError::from)
+            .and_then(|(data, _)| registrar::parse_host_record(&data));
+
+        match parsed {
+            Ok(host) => hosts.push(host),
+            Err(e) => warn!(log, "skipping host record";
+                            "path" => &child_path, "error" => e.to_string())
+        }
+    }
+
+    hosts
+}
+
+fn warn_conflicts(backends: &[HostRecord], log: &Logger) {
+    for address in haproxy::conflicting_addresses(backends) {
+        warn!(log, "host registered with both http and https, using http";
+              "address" => address.to_string());
+    }
+}
+
+// Returns whether haproxy is now running with `backends`.
+fn apply_backends(c: &Config, template: &str, backends: &[HostRecord],
+                  applier: &mut Applier, log: &Logger) -> bool {
+    warn_conflicts(backends, log);
+    let haproxy_config = c.get_haproxy();
+    let rendered = haproxy::render_config(backends, haproxy_config, template);
+
+    match applier.apply_if_changed(haproxy_config, &rendered, log) {
+        Ok(_) => true,
+        Err(e) => {
+            error!(log, "failed to apply haproxy config";
+                   "error" => e.to_string());
+            false
+        }
+    }
+}
+
+// Applies backend sets received from the watcher, debounced so that a burst
+// of changes only results in the latest set being applied once.
+fn apply_worker(rx: Receiver<Vec<HostRecord>>, c: Arc<Config>,
+                template: String, log: Logger) {
+    let mut debouncer = Debouncer::new(c.get_haproxy().get_reload_debounce());
+    let mut warmup = Warmup::new(c.get_haproxy().get_backend_warmup());
+    let mut guard = ShrinkGuard::new(
+        c.get_haproxy().get_min_backend_fraction(),
+        c.get_haproxy().get_min_backend_fraction_settle());
+    let mut applier = Applier::default();
+    let mut latest: Option<Vec<HostRecord>> = None;
 
-    let current_dir = env::current_dir().unwrap();
-    let default_config: PathBuf =
-        [current_dir, PathBuf::from("etc/config.json")].iter().collect();
-    let config_path = value_t!(matches, "file", PathBuf)
-        .unwrap_or(default_config);
-    println!("Value for config: {}", config_path.to_str().unwrap());
-
-    let config = config::read_file(config_path.as_path())
-        .expect("Failed to parse config");
-
-    //TODO: Runtime log handling
-    // By default slog makes the decision on what log lines to include at
-    // compile time. There is a way to do runtime selection though.
-    match matches.occurrences_of("verbose") {
-        0 => println!("No verbose info"),
-        1 => println!("Some verbose info"),
-        2 => println!("Tons of verbose info"),
-        3 | _ => println!("Don't be crazy"),
-    }
-
-    let root_log = Logger::root(
-        Mutex::new(
-            slog_bunyan::default(
-                std::io::stdout()
-            )
-        ).fuse(),
-        o!("build-id" => crate_version!())
+    loop {
+        let deadline = [debouncer.deadline(), warmup.deadline(),
+                        guard.deadline()]
+            .iter()
+            .flatten()
+            .min()
+            .cloned();
+        let received = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                let wait = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                rx.recv_timeout(wait)
+            },
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        };
+
+        let now = Instant::now();
+        match received {
+            Ok(backends) => {
+                warmup.observe(backends.iter().map(|h| &h.address), now);
+                debouncer.update(backends, now);
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return
+        }
+
+        let settled = guard.deadline().is_some_and(|d| now >= d);
+        let mut changed = warmup.promote(now) || settled;
+        if let Some(backends) = debouncer.take_ready(now) {
+            latest = Some(backends);
+            changed = true;
+        }
+        if !changed {
+            continue;
+        }
+
+        let backends: Vec<HostRecord> = match latest {
+            Some(ref hosts) => hosts.iter()
+                .filter(|h| warmup.is_warm(&h.address))
+                .cloned()
+                .collect(),
+            None => continue
+        };
+
+        if !guard.allow(backends.len(), now) {
+            warn!(log, "backend count dropped below minimum fraction, \
+                        keeping previous config";
+                  "previous" => guard.last_applied(),
+                  "current" => backends.len(),
+                  "min_fraction" => c.get_haproxy()
+                      .get_min_backend_fraction());
+            continue;
+        }
+
+        if apply_backends(&c, &template, &backends, &mut applier, &log) {
+            guard.applied(backends.len());
+        }
+    }
+}
+
+// slog is built with all levels compiled in so that the level can be chosen
+// at runtime with -v.
+fn verbosity_to_level(count: u64) -> Level {
+    match count {
+        0 => Level::Info,
+        1 => Level::Debug,
+        _ => Level::Trace
+    }
+}
+
+// Logs go to `console`, or to `log_file` (appended to) if given, or to both
+// if `tee` is set. The file is opened here, before anything is logged, so no
+// early lines are lost.
+fn build_logger(log_file: Option<&Path>, tee: bool,
+                console: Box<dyn Write + Send>, service: &str,
+                level: &LevelSwitch) -> io::Result<Logger> {
+    let fields = o!(
+        "build-id" => crate_version!(),
+        "service" => service.to_string()
     );
+    let drain = |out: Box<dyn Write + Send>| {
+        Mutex::new(slog_bunyan::default(out)).fuse()
+    };
+
+    let file = match log_file {
+        Some(path) => {
+            Some(OpenOptions::new().create(true).append(true).open(path)?)
+        },
+        None => None
+    };
+
+    let logger = match file {
+        Some(file) if tee => {
+            let both = slog::Duplicate::new(drain(Box::new(file)),
+                                            drain(console)).fuse();
+            Logger::root(level.filter(both).fuse(), fields)
+        },
+        Some(file) => {
+            Logger::root(level.filter(drain(Box::new(file))).fuse(), fields)
+        },
+        None => Logger::root(level.filter(drain(console)).fuse(), fields)
+    };
+
+    Ok(logger)
+}
+
+// Prints every IP found in `nic_file` or configured as untrusted, with
+// whether it would be treated as untrusted, without consulting mdata or
+// ZooKeeper.
+fn resolve_untrusted(c: &Config, nic_file: &Path, log: &Logger)
+                     -> Result<(), ExitCode> {
+    let nics = fs::read_to_string(nic_file).map_err(|e| {
+        eprintln!("Failed to read {}: {}", nic_file.display(), e);
+        ExitCode::Io
+    })?;
+    let nic_ips = config::parse_sdc_nics(&nics, c.get_strict_nic_parsing(),
+                                         log)
+        .map_err(|e| {
+            eprintln!("Failed to parse {}: {}", nic_file.display(), e);
+            ExitCode::Mdata
+        })?;
+
+    // The same set add_untrusted_ips builds: configured untrusted IPs are
+    // kept whatever their NIC, with each NIC address decided on its own.
+    let mut decisions: BTreeMap<IpAddr, String> = nic_ips
+        .iter()
+        .map(|(ip, nic_tag)| {
+            let decision = match c.untrusted_exclusion(ip, nic_tag) {
+                Some(reason) => format!("excluded: {}", reason),
+                None => "untrusted".to_string()
+            };
+            (*ip, decision)
+        })
+        .collect();
+    for ip in c.get_untrusted_ips() {
+        decisions.insert(*ip, "untrusted: configured".to_string());
+    }
+
+    for (ip, decision) in decisions {
+        println!("{}\t{}", ip, decision);
+    }
+
+    Ok(())
+}
+
+// Prints a summary of the already validated config. Where configs are checked
+// (e.g. in CI) there is usually no mdata-get, so NIC IPs are only counted if
+// mdata is available; sdc:nics that can be read but not parsed still fail.
+fn check<M: MdataProvider>(c: &mut Config, config_path: &Path, mdata: &M,
+                           log: &Logger) -> Result<(), ExitCode> {
+    haproxy::read_template(c.get_haproxy().get_template()).map_err(|e| {
+        eprintln!("Failed to read haproxy template: {}", e);
+        ExitCode::Config
+    })?;
+
+    match c.populate_untrusted_ips(mdata, log) {
+        Ok(()) => (),
+        Err(MuppetError::MdataCommand(e)) => {
+            eprintln!("mdata unavailable, not counting sdc nic ips: {}", e);
+        },
+        Err(e) => {
+            eprintln!("Failed adding sdc nic ips to config: {}", e);
+            return Err(ExitCode::Mdata);
+        }
+    }
+
+    println!("{}: config ok: 1 trusted, {} admin, {} manta, {} untrusted ips, \
+              {} zookeeper servers",
+             config_path.display(),
+             c.get_admin_ips().len(),
+             c.get_manta_ips().len(),
+             c.get_untrusted_ips().len(),
+             c.get_zookeeper().get_server_count());
+
+    Ok(())
+}
+
+// For each server's view of the service, the hosts that some other server
+// sees but it doesn't.
+fn missing_children(views: &[(String, BTreeSet<String>)])
+                    -> Vec<Vec<&String>> {
+    let all: BTreeSet<&String> = views
+        .iter()
+        .flat_map(|(_, children)| children.iter())
+        .collect();
+
+    views.iter()
+        .map(|(_, children)| {
+            all.iter().filter(|c| !children.contains(**c)).cloned().collect()
+        })
+        .collect()
+}
+
+// Reads the service's children from each ZooKeeper server individually and
+// reports any server whose view differs from the others.
+fn check_ensemble(c: &Config, log: &Logger) -> Result<(), ExitCode> {
+    let path = c.get_zk_path();
+    let timeout = Duration::from_millis(c.get_zookeeper().get_timeout());
+    let mut views: Vec<(String, BTreeSet<String>)> = Vec::new();
+
+    for server in c.get_zookeeper().server_connection_strings() {
+        info!(log, "reading children"; "server" => &server, "path" => &path);
+        let children = ZooKeeper::connect(&server, timeout,
+                                          |_: WatchedEvent| {})
+            .and_then(|zk| zk.get_children(&path, false))
+            .map_err(|e| {
+                eprintln!("Failed to read {} from {}: {:?}", path, server, e);
+                ExitCode::Zookeeper
+            })?;
+        views.push((server, children.into_iter().collect()));
+    }
+
+    let mut consistent = true;
+
+    for ((server, children), missing) in views.iter()
+        .zip(missing_children(&views)) {
+        if missing.is_empty() {
+            println!("{}: {} hosts", server, children.len());
+        } else {
+            consistent = false;
+            println!("{}: {} hosts, missing {:?}", server, children.len(),
+                     missing);
+        }
+    }
+
+    if !consistent {
+        return Err(ExitCode::Inconsistent);
+    }
+
+    Ok(())
+}
+
+fn write_rendered(out: &mut dyn Write, hosts: &[HostRecord], c: &Config,
+                  template: &str) -> io::Result<()> {
+    let rendered = haproxy::render_config(hosts, c.get_haproxy(), template);
+    writeln!(out, "# ---- {} backends ----", hosts.len())?;
+    writeln!(out, "{}", rendered)
+}
+
+// Prints the haproxy config for the service's current backends, and with
+// `watch` keeps printing it as they change. haproxy itself is never touched.
+fn render(c: Arc<Config>, watch: bool, log: &Logger) -> Result<(), ExitCode> {
+    let template = haproxy::read_template(c.get_haproxy().get_template())
+        .map_err(|e| {
+            eprintln!("Failed to read haproxy template: {}", e);
+            ExitCode::Config
+        })?;
+    let zk = zookeeper_session(&c, log, |_: WatchedEvent| {}).map_err(|e| {
+        eprintln!("Failed to connect to zk: {}", e);
+        ExitCode::Zookeeper
+    })?;
+    let zk = Arc::new(zk);
+    let path = c.get_zk_path();
+
+    let print_zk = Arc::clone(&zk);
+    let print_config = Arc::clone(&c);
+    let print_log = log.clone();
+    let print = move |children: Vec<String>| {
+        let hosts = read_hosts(&print_zk, &path, &children, &print_log);
+        warn_conflicts(&hosts, &print_log);
+        let stdout = io::stdout();
+        if let Err(e) = write_rendered(&mut stdout.lock(), &hosts,
+                                       &print_config, &template) {
+            error!(print_log, "failed to print config";
+                   "error" => e.to_string());
+        }
+    };
+
+    if !watch {
+        let children = zk.get_children(&c.get_zk_path(), false)
+            .map_err(|e| {
+                eprintln!("Failed to read service: {:?}", e);
+                ExitCode::Zookeeper
+            })?;
+        print(children);
+        return Ok(());
+    }
+
+    let (lost_tx, lost_rx) = mpsc::channel();
+    let lost_tx = Mutex::new(lost_tx);
+    start_watch(zk, &c, log, print, move || {
+        let _ = lost_tx.lock().unwrap().send(());
+    }).map_err(|e| {
+        eprintln!("Failed to watch service: {}", e);
+        ExitCode::Zookeeper
+    })?;
+
+    let _ = lost_rx.recv();
+    eprintln!("Lost the watch on the service");
+    Err(ExitCode::Zookeeper)
+}
+
+fn run() -> Result<(), ExitCode> {
+    let signals = loglevel::block_signals();
+    let matches = opts::parse(APP.to_string());
+
+    if let Some(init_matches) = matches.subcommand_matches("init-config") {
+        let path = init_matches.value_of("path").unwrap();
+        config::write_sample(path).map_err(|e| {
+            eprintln!("Failed to write sample config: {}", e);
+            ExitCode::Io
+        })?;
+        println!("Wrote sample config to {}", path);
+        return Ok(());
+    }
+
+    let config_path = opts::get_config_path(&matches).map_err(|e| {
+        eprintln!("Failed to locate config: {}", e);
+        ExitCode::Config
+    })?;
+    eprintln!("Value for config: {}", config_path.to_str().unwrap());
+
+    let mut config = config::read_source(config_path.to_str().unwrap(),
+                                         matches.value_of("profile"))
+        .map_err(|e| {
+            eprintln!("Failed to parse config: {}", e);
+            ExitCode::Config
+        })?;
+
+    let level = verbosity_to_level(opts::get_verbose_count(&matches));
+    let level_switch = LevelSwitch::new(level);
+    let log_file = matches.value_of("log-file").map(Path::new);
+    // Subcommands print their results for a caller to consume, so they keep
+    // stdout to themselves.
+    let console: Box<dyn Write + Send> =
+        if matches.subcommand_name().is_some() {
+            Box::new(io::stderr())
+        } else {
+            Box::new(io::stdout())
+        };
+    let root_log = build_logger(log_file, matches.is_present("log-tee"),
+                                console, config.get_name(), &level_switch)
+        .map_err(|e| {
+            eprintln!("Failed to open log file {}: {}",
+                      log_file.unwrap().display(), e);
+            ExitCode::Io
+        })?;
+    loglevel::listen_for_signals(signals, level_switch, level,
+                                 root_log.clone());
+
+    if let Some(resolve_matches) =
+        matches.subcommand_matches("resolve-untrusted") {
+        let nic_file = Path::new(resolve_matches.value_of("nic-file").unwrap());
+        return resolve_untrusted(&config, nic_file, &root_log);
+    }
+
+    if matches.subcommand_matches("check-ensemble").is_some() {
+        return check_ensemble(&config, &root_log);
+    }
+
+    if let Some(render_matches) = matches.subcommand_matches("render") {
+        return render(Arc::new(config), render_matches.is_present("watch"),
+                      &root_log);
+    }
+
+    let mdata = CommandMdata::new(config.get_mdata_bin(),
+                                  config.get_mdata_key());
+
+    if matches.is_present("check") {
+        return check(&mut config, &config_path, &mdata, &root_log);
+    }
+
+    config.populate_untrusted_ips(&mdata, &root_log).map_err(|e| {
+        eprintln!("Failed adding sdc nic ips to config: {}", e);
+        ExitCode::Mdata
+    })?;
+    info!(root_log, "untrusted ips";
+          "ips" => format!("{:?}", config.get_untrusted_ips()));
+
+    if matches.subcommand_matches("dump-config").is_some() {
+        let dumped = serde_json::to_string_pretty(&config).map_err(|e| {
+            eprintln!("Failed to serialize config: {}", e);
+            ExitCode::Config
+        })?;
+        println!("{}", dumped);
+        return Ok(());
+    }
 
     info!(root_log, "muppet has started");
 
-    let untrusted_ips = get_untrusted_ips(&config);
+    let config = Arc::new(config);
+
+    let template = haproxy::read_template(config.get_haproxy().get_template())
+        .map_err(|e| {
+            eprintln!("Failed to read haproxy template: {}", e);
+            ExitCode::Config
+        })?;
+
+    let (tx, rx) = mpsc::channel();
+    let worker_config = Arc::clone(&config);
+    let worker_log = root_log.clone();
+    thread::spawn(move || {
+        apply_worker(rx, worker_config, template, worker_log)
+    });
+    let watch_tx = Arc::new(Mutex::new(tx));
+
+    // Signalled when the session expires or the watch is lost.
+    let (reconnect_tx, reconnect_rx) = mpsc::channel();
+    let mut watch_backoff = zk_backoff();
+    loop {
+        while reconnect_rx.try_recv().is_ok() {}
+
+        let zk = Arc::new(connect_with_backoff(&config, &root_log,
+                                               &reconnect_tx));
+        let session = Arc::clone(&zk);
+        let watch_zk = Arc::clone(&zk);
+        let watch_path = config.get_zk_path();
+        let watch_log = root_log.clone();
+        let watch_tx = Arc::clone(&watch_tx);
+        let lost_tx = Mutex::new(reconnect_tx.clone());
+        let watched = start_watch(zk, &config, &root_log, move |children| {
+            let hosts = read_hosts(&watch_zk, &watch_path, &children,
+                                   &watch_log);
+            info!(watch_log, "backends changed"; "count" => hosts.len());
+
+            if watch_tx.lock().unwrap().send(hosts).is_err() {
+                error!(watch_log, "haproxy apply worker has exited");
+            }
+        }, move || {
+            let _ = lost_tx.lock().unwrap().send(());
+        });
+
+        match watched {
+            Ok(()) => {
+                watch_backoff = zk_backoff();
+
+                // The watch callbacks do the rest of the work until the
+                // session expires or the watch can't be re-armed, at which
+                // point we start over.
+                let _ = reconnect_rx.recv();
+            },
+            Err(e) => {
+                let delay = backoff::with_jitter(watch_backoff.next_delay());
+                warn!(root_log, "failed to watch service, retrying";
+                      "error" => e.to_string(),
+                      "delay_ms" => delay.as_secs() * 1000 +
+                                    u64::from(delay.subsec_millis()));
+                thread::sleep(delay);
+            }
+        }
+
+        // The watch callbacks hold references to the session, so it won't
+        // be dropped on its own; close it so it doesn't linger alongside
+        // the new one.
+        if let Err(e) = session.close() {
+            debug!(root_log, "failed to close zookeeper session";
+                   "error" => format!("{:?}", e));
+        }
+        info!(root_log, "re-establishing zookeeper session");
+    }
+}
+
+fn main() {
+    if let Err(code) = run() {
+        process::exit(code.code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::temp_path;
+
+    // A writer that can still be read after being boxed up and handed to a
+    // logger.
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedBuf {
+        fn new() -> SharedBuf {
+            SharedBuf(Arc::new(Mutex::new(Vec::new())))
+        }
+
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logger_tees_to_file_and_console() {
+        let path = temp_path("tee.log");
+        let console = SharedBuf::new();
+
+        let log = build_logger(Some(&path), true, Box::new(console.clone()),
+                               "test", &LevelSwitch::new(Level::Info))
+            .unwrap();
+        info!(log, "to both drains");
+
+        let file_contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert!(file_contents.contains("to both drains"));
+        assert!(console.contents().contains("to both drains"));
+    }
+
+    #[test]
+    fn logger_file_replaces_console() {
+        let path = temp_path("file.log");
+        let console = SharedBuf::new();
+
+        let log = build_logger(Some(&path), false, Box::new(console.clone()),
+                               "test", &LevelSwitch::new(Level::Info))
+            .unwrap();
+        info!(log, "to the file only");
+
+        let file_contents = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        assert!(file_contents.contains("to the file only"));
+        assert_eq!(console.contents(), "");
+    }
+
+    #[test]
+    fn logger_tags_service_name() {
+        let console = SharedBuf::new();
+
+        let log = build_logger(None, false, Box::new(console.clone()),
+                               "1.moray.example.joyent.us",
+                               &LevelSwitch::new(Level::Info)).unwrap();
+        info!(log, "tagged");
+
+        assert!(console.contents()
+                .contains("\"service\":\"1.moray.example.joyent.us\""));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(ExitCode::Config.code(), 2);
+        assert_eq!(ExitCode::Mdata.code(), 3);
+        assert_eq!(ExitCode::Zookeeper.code(), 4);
+        assert_eq!(ExitCode::Io.code(), 5);
+        assert_eq!(ExitCode::Inconsistent.code(), 6);
+    }
+
+    #[test]
+    fn verbosity_levels() {
+        assert_eq!(verbosity_to_level(0), Level::Info);
+        assert_eq!(verbosity_to_level(1), Level::Debug);
+        assert_eq!(verbosity_to_level(2), Level::Trace);
+        assert_eq!(verbosity_to_level(5), Level::Trace);
+    }
+
+    #[test]
+    fn render_output() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let c = config::read_file(data.join("config.json"), None).unwrap();
+        let template = fs::read_to_string(data.join("haproxy.cfg.in"))
+            .unwrap();
+        let hosts: Vec<HostRecord> = [
+            r#"{ "type": "load_balancer", "address": "10.0.0.2" }"#,
+            r#"{ "type": "load_balancer", "address": "10.0.0.1" }"#
+        ].iter()
+            .map(|r| registrar::parse_host_record(r.as_bytes()).unwrap())
+            .collect();
+        let mut out = Vec::new();
+
+        write_rendered(&mut out, &hosts, &c, &template).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("# ---- 2 backends ----\nglobal\n"));
+        assert!(out.ends_with("    server be_0 10.0.0.1:80 check\n\
+                               \x20   server be_1 10.0.0.2:80 check\n\n"));
+    }
+
+    fn session_event(state: KeeperState) -> WatchedEvent {
+        WatchedEvent {
+            event_type: zookeeper::WatchedEventType::None,
+            keeper_state: state,
+            path: None
+        }
+    }
+
+    #[test]
+    fn only_expiry_triggers_reconnect() {
+        let log = Logger::root(slog::Discard, o!());
+        let expired = std::cell::Cell::new(0);
+        let on_expired = || expired.set(expired.get() + 1);
+
+        for state in [KeeperState::SyncConnected,
+                      KeeperState::Disconnected,
+                      KeeperState::AuthFailed] {
+            handle_session_event(&session_event(state), &log, on_expired);
+        }
+        assert_eq!(expired.get(), 0);
+
+        handle_session_event(&session_event(KeeperState::Expired), &log,
+                             on_expired);
+        assert_eq!(expired.get(), 1);
+    }
+
+    fn view(server: &str, children: &[&str]) -> (String, BTreeSet<String>) {
+        (server.to_string(), children.iter().map(|c| c.to_string()).collect())
+    }
+
+    #[test]
+    fn ensemble_views_agree() {
+        let views = vec![view("zk1", &["a", "b"]), view("zk2", &["b", "a"])];
+        assert!(missing_children(&views).iter().all(|m| m.is_empty()));
+    }
 
-    let zk_result = zookeeper_session(&config, untrusted_ips);
+    #[test]
+    fn ensemble_views_diverge() {
+        let views = vec![
+            view("zk1", &["a", "b"]),
+            view("zk2", &["a"]),
+            view("zk3", &["c"])
+        ];
 
-    match zk_result {
-        Ok(zk_session) => start_watch(&zk_session, &config),
-        Err(_)         =>     println!("Failed to connect to zk")
+        assert_eq!(missing_children(&views),
+                   vec![vec!["c"], vec!["b", "c"], vec!["a", "b"]]);
     }
 }
@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::MuppetError;
+
+// Source of the zone's metadata. Production reads it with mdata-get; tests
+// can supply canned data instead.
+pub trait MdataProvider {
+    // The JSON value of the sdc:nics key (or whichever key is configured).
+    fn get_nics(&self) -> Result<String, MuppetError>;
+}
+
+pub struct CommandMdata {
+    bin: PathBuf,
+    key: String
+}
+
+impl CommandMdata {
+    pub fn new(bin: &Path, key: &str) -> CommandMdata {
+        CommandMdata {
+            bin: bin.to_path_buf(),
+            key: key.to_string()
+        }
+    }
+}
+
+impl MdataProvider for CommandMdata {
+    fn get_nics(&self) -> Result<String, MuppetError> {
+        let output = Command::new(&self.bin)
+            .arg(&self.key)
+            .output()
+            .map_err(|e| MuppetError::MdataCommand(
+                format!("failed to run {}: {}", self.bin.display(), e)))?;
+
+        if !output.status.success() {
+            return Err(MuppetError::MdataCommand(
+                format!("{} {} failed: {}", self.bin.display(), self.key,
+                        String::from_utf8_lossy(&output.stderr).trim())));
+        }
+
+        String::from_utf8(output.stdout)
+            .map_err(|e| MuppetError::MdataCommand(
+                format!("{} returned invalid UTF-8: {}",
+                        self.bin.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::testutil::fake_command;
+
+    #[test]
+    fn command_output() {
+        let bin = fake_command(
+            "mdata-ok",
+            "[ \"$1\" = \"sdc:nics\" ] || exit 1\n\
+             echo '[{ \"ip\": \"10.0.0.1\" }]'");
+
+        let nics = CommandMdata::new(&bin, "sdc:nics").get_nics();
+        let _ = fs::remove_file(&bin);
+        assert_eq!(nics.unwrap(), "[{ \"ip\": \"10.0.0.1\" }]\n");
+    }
+
+    #[test]
+    fn command_failure() {
+        let bin = fake_command("mdata-fail",
+                               "echo \"No metadata for '$1'\" >&2\nexit 1");
+
+        let nics = CommandMdata::new(&bin, "sdc:nics").get_nics();
+        let _ = fs::remove_file(&bin);
+        let err = nics.unwrap_err().to_string();
+        assert!(err.contains("No metadata for 'sdc:nics'"), "{}", err);
+    }
+
+    #[test]
+    fn command_missing() {
+        let mdata = CommandMdata::new(Path::new("/nonexistent/mdata-get"),
+                                      "sdc:nics");
+        assert!(mdata.get_nics().is_err());
+    }
+}
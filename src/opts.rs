@@ -2,15 +2,26 @@
  * Copyright (c) 2019, Joyent, Inc.
  */
 
-use clap::{App, Arg, ArgMatches, crate_version};
+use std::env;
+use std::io;
+use std::path::PathBuf;
 
-static ABOUT: &'static str = "Muppet is an HTTP loadbalancer (haproxy) and \
-                              small daemon that interacts with ZooKeeper via \
-                              registrar. The muppet daemon will update the \
-                              loadbalancer with new configuration as hosts \
-                              come and go from the given service name.";
+use clap::{App, Arg, ArgMatches, SubCommand, crate_version, value_t};
 
-pub fn parse<'a, 'b>(app: String) -> ArgMatches<'a> {
+static ABOUT: &str = "Muppet is an HTTP loadbalancer (haproxy) and \
+                      small daemon that interacts with ZooKeeper via \
+                      registrar. The muppet daemon will update the \
+                      loadbalancer with new configuration as hosts \
+                      come and go from the given service name.";
+
+static DEFAULT_CONFIG: &str = "etc/config.json";
+static CONFIG_ENV_VAR: &str = "MUPPET_CONFIG";
+
+pub fn parse<'a>(app: String) -> ArgMatches<'a> {
+    build(app).get_matches()
+}
+
+fn build<'a, 'b>(app: String) -> App<'a, 'b> {
     App::new(app)
         .about(ABOUT)
         .version(crate_version!())
@@ -20,12 +31,175 @@ pub fn parse<'a, 'b>(app: String) -> ArgMatches<'a> {
              .long("file")
              .takes_value(true)
              .required(false))
+        .arg(Arg::with_name("profile")
+             .help("Named profile to use from the configuration file")
+             .short("p")
+             .long("profile")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("log-file")
+             .help("Write log output to this file instead of stdout")
+             .long("log-file")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("log-tee")
+             .help("With --log-file, also write log output to stdout")
+             .long("log-tee")
+             .takes_value(false)
+             .requires("log-file")
+             .required(false))
+        .arg(Arg::with_name("check")
+             .help("Validate the configuration and exit")
+             .long("check")
+             .takes_value(false)
+             .required(false))
         .arg(Arg::with_name("verbose")
-             .help("Verbose output. Use multiple times for more verbose.")
+             .help("Verbose output. Use multiple times for more verbose. \
+                    While running, SIGUSR1 raises the level one step and \
+                    SIGUSR2 restores it.")
              .short("v")
              .long("verbose")
              .multiple(true)
              .takes_value(false)
              .required(false))
-        .get_matches()
+        .subcommand(SubCommand::with_name("init-config")
+                    .about("Write a sample configuration file")
+                    .arg(Arg::with_name("path")
+                         .help("Where to write the sample configuration")
+                         .index(1)
+                         .required(true)))
+        .subcommand(SubCommand::with_name("resolve-untrusted")
+                    .about("Show which IPs from an sdc:nics file would be \
+                            treated as untrusted, and why")
+                    .arg(Arg::with_name("nic-file")
+                         .help("File containing sdc:nics JSON")
+                         .index(1)
+                         .required(true)))
+        .subcommand(SubCommand::with_name("check-ensemble")
+                    .about("Compare the service's hosts as seen by each \
+                            ZooKeeper server"))
+        .subcommand(SubCommand::with_name("dump-config")
+                    .about("Print the fully resolved configuration as JSON"))
+        .subcommand(SubCommand::with_name("render")
+                    .about("Print the haproxy config for the current \
+                            backends without applying it")
+                    .arg(Arg::with_name("watch")
+                         .help("Keep printing the config each time the \
+                                backends change")
+                         .long("watch")
+                         .takes_value(false)
+                         .required(false)))
+}
+
+// The default config lives relative to the working directory. If that can't
+// be determined (e.g. it has been removed out from under us) fall back to
+// the directory holding the executable rather than failing to start. If
+// neither is known there is no sensible default and the config has to be
+// given explicitly.
+fn default_config_path(current_dir: io::Result<PathBuf>,
+                       current_exe: io::Result<PathBuf>)
+                       -> io::Result<PathBuf> {
+    let base = match current_dir {
+        Ok(dir) => dir,
+        Err(dir_err) => {
+            let exe_dir = current_exe.and_then(|exe| {
+                exe.parent().map(|d| d.to_path_buf()).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound,
+                                   "executable has no parent directory")
+                })
+            });
+            match exe_dir {
+                Ok(dir) => {
+                    eprintln!("Unable to determine current directory ({}), \
+                               looking for config relative to {}",
+                              dir_err, dir.display());
+                    dir
+                },
+                Err(exe_err) => {
+                    return Err(io::Error::new(dir_err.kind(), format!(
+                        "unable to determine the current directory ({}) or \
+                         the executable's ({}), use -f or {} to give the \
+                         config path", dir_err, exe_err, CONFIG_ENV_VAR)));
+                }
+            }
+        }
+    };
+
+    Ok(base.join(DEFAULT_CONFIG))
+}
+
+// In order of precedence: -f/--file, the MUPPET_CONFIG environment variable,
+// then the default location. An empty MUPPET_CONFIG counts as unset.
+pub fn get_config_path(matches: &ArgMatches) -> io::Result<PathBuf> {
+    let given = value_t!(matches, "file", PathBuf)
+        .ok()
+        .or_else(|| {
+            env::var_os(CONFIG_ENV_VAR)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        });
+
+    match given {
+        Some(path) => Ok(path),
+        None => default_config_path(env::current_dir(), env::current_exe())
+    }
+}
+
+pub fn get_verbose_count(matches: &ArgMatches) -> u64 {
+    matches.occurrences_of("verbose")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unavailable() -> io::Result<PathBuf> {
+        Err(io::Error::new(io::ErrorKind::NotFound, "unavailable"))
+    }
+
+    #[test]
+    fn default_path_from_current_dir() {
+        let path = default_config_path(Ok(PathBuf::from("/opt/muppet")),
+                                       unavailable()).unwrap();
+        assert_eq!(path, PathBuf::from("/opt/muppet/etc/config.json"));
+    }
+
+    #[test]
+    fn default_path_falls_back_to_exe_dir() {
+        let path = default_config_path(
+            unavailable(), Ok(PathBuf::from("/opt/muppet/bin/muppet")))
+            .unwrap();
+        assert_eq!(path, PathBuf::from("/opt/muppet/bin/etc/config.json"));
+    }
+
+    #[test]
+    fn default_path_unavailable() {
+        assert!(default_config_path(unavailable(), unavailable()).is_err());
+    }
+
+    fn config_path(args: &[&str]) -> PathBuf {
+        let matches = build("muppet".to_string())
+            .get_matches_from(Some("muppet").iter().chain(args));
+        get_config_path(&matches).unwrap()
+    }
+
+    // The environment is shared by every test thread, so all the cases that
+    // touch MUPPET_CONFIG run here in turn.
+    #[test]
+    fn config_path_precedence() {
+        let default = env::current_dir().unwrap().join(DEFAULT_CONFIG);
+
+        env::remove_var(CONFIG_ENV_VAR);
+        assert_eq!(config_path(&[]), default);
+
+        env::set_var(CONFIG_ENV_VAR, "");
+        assert_eq!(config_path(&[]), default);
+
+        env::set_var(CONFIG_ENV_VAR, "/opt/muppet/env.json");
+        assert_eq!(config_path(&[]), PathBuf::from("/opt/muppet/env.json"));
+        assert_eq!(config_path(&["-f", "/opt/muppet/flag.json"]),
+                   PathBuf::from("/opt/muppet/flag.json"));
+
+        env::remove_var(CONFIG_ENV_VAR);
+    }
 }
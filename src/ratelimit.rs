@@ -0,0 +1,76 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::time::{Duration, Instant};
+
+// Allows at most `limit` events per `interval`. Events beyond that are
+// counted rather than allowed, and the count is handed back with the next
+// allowed event so the caller can report how many were suppressed.
+pub struct RateLimiter {
+    limit: u32,
+    interval: Duration,
+    window_start: Option<Instant>,
+    allowed: u32,
+    suppressed: u64
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, interval: Duration) -> RateLimiter {
+        RateLimiter {
+            limit,
+            interval,
+            window_start: None,
+            allowed: 0,
+            suppressed: 0
+        }
+    }
+
+    // Returns the number of events suppressed since the last allowed one if
+    // this event is allowed, or None if it should be suppressed.
+    pub fn allow(&mut self, now: Instant) -> Option<u64> {
+        let expired = match self.window_start {
+            Some(start) => now.duration_since(start) >= self.interval,
+            None => true
+        };
+        if expired {
+            self.window_start = Some(now);
+            self.allowed = 0;
+        }
+
+        if self.allowed >= self.limit {
+            self.suppressed += 1;
+            return None;
+        }
+
+        self.allowed += 1;
+        let suppressed = self.suppressed;
+        self.suppressed = 0;
+
+        Some(suppressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suppresses_and_reports() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+        let mut limiter = RateLimiter::new(2, interval);
+
+        assert_eq!(limiter.allow(start), Some(0));
+        assert_eq!(limiter.allow(start), Some(0));
+        assert_eq!(limiter.allow(start), None);
+        assert_eq!(limiter.allow(start + Duration::from_secs(30)), None);
+        assert_eq!(limiter.allow(start + Duration::from_secs(59)), None);
+
+        // A new interval allows events again, reporting what was missed.
+        let next = start + interval;
+        assert_eq!(limiter.allow(next), Some(3));
+        assert_eq!(limiter.allow(next), Some(0));
+        assert_eq!(limiter.allow(next), None);
+    }
+}
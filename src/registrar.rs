@@ -0,0 +1,70 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::net::IpAddr;
+
+use serde_derive::Deserialize;
+
+use crate::error::MuppetError;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    Https
+}
+
+// The JSON record registrar writes into each host znode under a service.
+// Only the fields muppet renders are read; registrar's others (the record
+// type, ports and so on) are ignored.
+#[derive(Clone, Debug, Deserialize)]
+pub struct HostRecord {
+    pub address: IpAddr,
+    // How haproxy should talk to the host. Plain http if not given.
+    pub scheme: Option<Scheme>
+}
+
+impl HostRecord {
+    pub fn is_https(&self) -> bool {
+        self.scheme == Some(Scheme::Https)
+    }
+}
+
+pub fn parse_host_record(data: &[u8]) -> Result<HostRecord, MuppetError> {
+    serde_json::from_slice(data)
+        .map_err(|e| MuppetError::Registrar(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_registrar_payload() {
+        let payload = br#"{
+            "type": "load_balancer",
+            "address": "10.77.77.12",
+            "ports": [80, 443],
+            "load_balancer": { "address": "10.77.77.12" },
+            "ttl": 30
+        }"#;
+
+        let host = parse_host_record(payload).unwrap();
+        assert_eq!(host.address, "10.77.77.12".parse::<IpAddr>().unwrap());
+        assert!(!host.is_https());
+    }
+
+    #[test]
+    fn parse_without_ports() {
+        let host = parse_host_record(
+            br#"{ "type": "host", "address": "10.77.77.13" }"#).unwrap();
+        assert_eq!(host.address, "10.77.77.13".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn parse_malformed() {
+        let err = parse_host_record(b"{ \"type\": ").unwrap_err();
+        assert!(err.to_string().contains("invalid registrar record"));
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::time::{Duration, Instant};
+
+// Refuses a backend set smaller than `min_fraction` of the last one applied,
+// keeping the previous config instead. Only if `settle` is given is a smaller
+// set that keeps the same size for that long let through, for operators who
+// would rather follow a lasting loss of hosts than hold the old config.
+pub struct ShrinkGuard {
+    min_fraction: Option<f64>,
+    settle: Option<Duration>,
+    applied: Option<usize>,
+    refused: Option<(usize, Instant)>
+}
+
+impl ShrinkGuard {
+    pub fn new(min_fraction: Option<f64>, settle: Option<Duration>)
+               -> ShrinkGuard {
+        ShrinkGuard {
+            min_fraction,
+            settle,
+            applied: None,
+            refused: None
+        }
+    }
+
+    // Whether a set of `count` backends may be applied.
+    pub fn allow(&mut self, count: usize, now: Instant) -> bool {
+        let (fraction, last) = match (self.min_fraction, self.applied) {
+            (Some(fraction), Some(last)) => (fraction, last),
+            _ => return true
+        };
+        if (count as f64) >= (last as f64) * fraction {
+            self.refused = None;
+            return true;
+        }
+
+        let settle = match self.settle {
+            Some(settle) => settle,
+            None => return false
+        };
+        match self.refused {
+            Some((refused, since)) if refused == count => {
+                if now < since + settle {
+                    return false;
+                }
+                self.refused = None;
+                true
+            },
+            _ => {
+                self.refused = Some((count, now));
+                false
+            }
+        }
+    }
+
+    // Records a successful apply of `count` backends as the new baseline.
+    pub fn applied(&mut self, count: usize) {
+        self.applied = Some(count);
+        self.refused = None;
+    }
+
+    pub fn last_applied(&self) -> Option<usize> {
+        self.applied
+    }
+
+    // When a refused set will have settled.
+    pub fn deadline(&self) -> Option<Instant> {
+        match (self.refused, self.settle) {
+            (Some((_, since)), Some(settle)) => Some(since + settle),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(settle: Option<Duration>, start: Instant) -> ShrinkGuard {
+        let mut guard = ShrinkGuard::new(Some(0.5), settle);
+        assert!(guard.allow(10, start));
+        guard.applied(10);
+        guard
+    }
+
+    fn settling(start: Instant) -> ShrinkGuard {
+        guard(Some(Duration::from_secs(600)), start)
+    }
+
+    #[test]
+    fn small_set_trips() {
+        let start = Instant::now();
+        let mut guard = guard(None, start);
+
+        assert!(!guard.allow(4, start));
+        assert!(guard.allow(5, start));
+        assert!(guard.allow(20, start));
+        assert_eq!(guard.deadline(), None);
+    }
+
+    #[test]
+    fn small_set_refused_for_good_without_settle() {
+        let start = Instant::now();
+        let mut guard = guard(None, start);
+
+        assert!(!guard.allow(4, start));
+        assert_eq!(guard.deadline(), None);
+        assert!(!guard.allow(4, start + Duration::from_secs(86400)));
+        assert_eq!(guard.last_applied(), Some(10));
+    }
+
+    #[test]
+    fn failed_apply_keeps_baseline() {
+        let start = Instant::now();
+        let mut guard = guard(None, start);
+
+        // 8 is allowed but never applied, so 4 is still judged against 10.
+        assert!(guard.allow(8, start));
+        assert!(!guard.allow(4, start));
+        assert_eq!(guard.last_applied(), Some(10));
+    }
+
+    #[test]
+    fn settled_set_rebaselines() {
+        let start = Instant::now();
+        let mut guard = settling(start);
+        let settled = start + Duration::from_secs(600);
+
+        assert!(!guard.allow(4, start));
+        assert_eq!(guard.deadline(), Some(settled));
+        assert!(!guard.allow(4, settled - Duration::from_secs(1)));
+        assert!(guard.allow(4, settled));
+        guard.applied(4);
+
+        assert!(!guard.allow(1, settled));
+        assert!(guard.allow(2, settled));
+    }
+
+    #[test]
+    fn changing_set_restarts_settling() {
+        let start = Instant::now();
+        let mut guard = settling(start);
+        let later = start + Duration::from_secs(300);
+
+        assert!(!guard.allow(4, start));
+        assert!(!guard.allow(3, later));
+        assert!(!guard.allow(3, start + Duration::from_secs(600)));
+        assert!(guard.allow(3, later + Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn no_fraction_allows_anything() {
+        let start = Instant::now();
+        let mut guard = ShrinkGuard::new(None, None);
+        guard.applied(10);
+
+        assert!(guard.allow(0, start));
+        assert_eq!(guard.deadline(), None);
+    }
+}
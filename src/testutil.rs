@@ -0,0 +1,35 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+// Helpers shared by the unit tests.
+
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process;
+
+use slog::Logger;
+
+// A path in the temp directory that won't collide with another test run.
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("muppet-{}-{}", process::id(), name))
+}
+
+// Writes an executable shell script to stand in for a command muppet runs.
+pub fn fake_command(name: &str, script: &str) -> PathBuf {
+    let path = temp_path(name);
+    fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+pub fn discard_log() -> Logger {
+    Logger::root(slog::Discard, slog::o!())
+}
+
+pub fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Holds back newly-seen backends until they have stayed registered for
+// `window`, so a backend flapping as it starts up doesn't churn the config.
+// A backend that disappears while warming starts over when it comes back.
+// Backends present in the first observation are taken as already warm, so
+// startup doesn't render an empty config.
+pub struct Warmup {
+    window: Duration,
+    primed: bool,
+    present: HashSet<IpAddr>,
+    warming: HashMap<IpAddr, Instant>
+}
+
+impl Warmup {
+    pub fn new(window: Duration) -> Warmup {
+        Warmup {
+            window,
+            primed: false,
+            present: HashSet::new(),
+            warming: HashMap::new()
+        }
+    }
+
+    pub fn observe<'a, I>(&mut self, addresses: I, now: Instant)
+        where I: IntoIterator<Item = &'a IpAddr>
+    {
+        let seen: HashSet<IpAddr> = addresses.into_iter().cloned().collect();
+
+        if self.primed && self.window > Duration::from_secs(0) {
+            for addr in seen.difference(&self.present) {
+                self.warming.insert(*addr, now + self.window);
+            }
+        }
+        self.warming.retain(|addr, _| seen.contains(addr));
+
+        self.present = seen;
+        self.primed = true;
+    }
+
+    // Marks backends whose window has passed as warm, returning whether any
+    // were.
+    pub fn promote(&mut self, now: Instant) -> bool {
+        let before = self.warming.len();
+        self.warming.retain(|_, ready| *ready > now);
+        self.warming.len() != before
+    }
+
+    pub fn is_warm(&self, addr: &IpAddr) -> bool {
+        !self.warming.contains_key(addr)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.warming.values().min().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testutil::ip;
+
+    #[test]
+    fn first_snapshot_is_warm() {
+        let start = Instant::now();
+        let mut warmup = Warmup::new(Duration::from_secs(30));
+
+        warmup.observe(&[ip("10.0.0.1")], start);
+        assert!(warmup.is_warm(&ip("10.0.0.1")));
+        assert_eq!(warmup.deadline(), None);
+    }
+
+    #[test]
+    fn new_backend_warms_up() {
+        let start = Instant::now();
+        let window = Duration::from_secs(30);
+        let mut warmup = Warmup::new(window);
+        warmup.observe(&[ip("10.0.0.1")], start);
+
+        warmup.observe(&[ip("10.0.0.1"), ip("10.0.0.2")], start);
+        assert!(!warmup.is_warm(&ip("10.0.0.2")));
+        assert_eq!(warmup.deadline(), Some(start + window));
+
+        assert!(!warmup.promote(start + window - Duration::from_secs(1)));
+        assert!(!warmup.is_warm(&ip("10.0.0.2")));
+        assert!(warmup.promote(start + window));
+        assert!(warmup.is_warm(&ip("10.0.0.2")));
+        assert_eq!(warmup.deadline(), None);
+    }
+
+    #[test]
+    fn flapping_backend_stays_excluded() {
+        let start = Instant::now();
+        let window = Duration::from_secs(30);
+        let mut warmup = Warmup::new(window);
+        let base = [ip("10.0.0.1")];
+        let with_new = [ip("10.0.0.1"), ip("10.0.0.2")];
+        warmup.observe(&base, start);
+
+        // Comes and goes every 10s, so never stays for the whole window.
+        for i in 0..3 {
+            let now = start + Duration::from_secs(i * 20);
+            warmup.observe(&with_new, now);
+            warmup.promote(now);
+            assert!(!warmup.is_warm(&ip("10.0.0.2")));
+
+            warmup.observe(&base, now + Duration::from_secs(10));
+            assert_eq!(warmup.deadline(), None);
+        }
+
+        // Then stays, and is warm a full window after it last came back.
+        let back = start + Duration::from_secs(60);
+        warmup.observe(&with_new, back);
+        assert!(!warmup.promote(back + window - Duration::from_secs(1)));
+        assert!(warmup.promote(back + window));
+        assert!(warmup.is_warm(&ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn zero_window_adds_straight_away() {
+        let start = Instant::now();
+        let mut warmup = Warmup::new(Duration::from_secs(0));
+        warmup.observe(&[ip("10.0.0.1")], start);
+
+        warmup.observe(&[ip("10.0.0.1"), ip("10.0.0.2")], start);
+        assert!(warmup.is_warm(&ip("10.0.0.2")));
+        assert_eq!(warmup.deadline(), None);
+    }
+}
@@ -0,0 +1,85 @@
+/*
+ * Copyright (c) 2019, Joyent, Inc.
+ */
+
+use std::process::{Command, Output};
+
+fn muppet(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust-muppet"))
+        .args(args)
+        .env_remove("MUPPET_CONFIG")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn missing_config_exits_2() {
+    let output = muppet(&["-f", "/nonexistent/muppet/config.json"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("Failed to parse config"));
+}
+
+#[test]
+fn check_good_config() {
+    let output = muppet(&["--check", "-f", "tests/data/config.json"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout)
+            .contains("tests/data/config.json: config ok: 1 trusted, \
+                       1 admin, 1 manta, 4 untrusted ips, \
+                       3 zookeeper servers"));
+}
+
+#[test]
+fn check_without_mdata() {
+    let output = muppet(&["--check", "-f", "tests/data/config-no-mdata.json"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout)
+            .contains("config ok: 1 trusted, 1 admin, 1 manta, \
+                       0 untrusted ips, 3 zookeeper servers"));
+}
+
+#[test]
+fn check_malformed_config() {
+    let output = muppet(&["--check", "-f", "tests/data/malformed.json"]);
+
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn dump_config_snapshot() {
+    let output = muppet(&["-f", "tests/data/config.json", "dump-config"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               include_str!("data/dump-config.json"));
+}
+
+#[test]
+fn check_template_without_servers() {
+    let output = muppet(&["--check", "-f",
+                          "tests/data/config-no-servers.json"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr)
+            .contains("has no {{servers}} placeholder"));
+}
+
+#[test]
+fn resolve_untrusted_includes_configured() {
+    let output = muppet(&["-f", "tests/data/config-untrusted.json",
+                          "resolve-untrusted", "tests/data/sdc-nics.json"]);
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout),
+               "10.1.0.1\texcluded: listed in adminIPs\n\
+                10.2.0.1\texcluded: listed in mantaIPs\n\
+                165.225.1.10\tuntrusted\n\
+                172.16.0.5\tuntrusted: configured\n\
+                192.168.128.5\texcluded: nic_tag mantanat is excluded\n\
+                203.0.113.7\tuntrusted: configured\n\
+                2001:db8::10\tuntrusted\n");
+}